@@ -0,0 +1,63 @@
+//! Serialization of timestamps, and whole rounds submitted over loopback to a mock calendar.
+//!
+//! Needs the mock calendar: `cargo bench --features mock-calendar`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tokio::runtime::Runtime;
+
+use foxglove::aggregator::{LinearTimestamp, StampRequest, aggregate_requests};
+use foxglove::mock_calendar::{MockCalendar, Reply};
+use foxglove::trees::{TreeBuilder, TreeShape};
+use foxglove::upstream::{Upstreams, UpstreamsConfig};
+
+/// Aggregates `n` requests into a single round, returning their timestamps.
+async fn round(n: usize, upstreams: &Upstreams) -> Vec<LinearTimestamp> {
+    let mut requests = Vec::with_capacity(n);
+    let mut receivers = Vec::with_capacity(n);
+    let mut tree = TreeBuilder::new(TreeShape::Duplicate);
+    for i in 0 .. n {
+        let (request, receiver) = StampRequest::new(&i.to_le_bytes());
+        tree.push(request.digest());
+        requests.push(request);
+        receivers.push(receiver);
+    }
+    aggregate_requests(requests, tree, upstreams).await;
+
+    let mut stamps = Vec::with_capacity(n);
+    for receiver in receivers {
+        stamps.push(receiver.await.unwrap().unwrap());
+    }
+    stamps
+}
+
+fn bench_round(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let calendar = runtime.block_on(MockCalendar::start(Reply::Proof));
+    let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+
+    let mut group = c.benchmark_group("serialize");
+    for n in [1, 1_000, 100_000] {
+        // Timestamps from a round of n requests, whose paths grow with log2(n)
+        let stamps = runtime.block_on(round(n, &upstreams));
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &stamps, |b, stamps| {
+            b.iter(|| stamps.iter().map(|stamp| stamp.serialize().len()).sum::<usize>())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("round");
+    for n in [1, 1_000, 100_000] {
+        if n >= 100_000 {
+            group.sample_size(10);
+        }
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &n, |b, &n| {
+            b.to_async(&runtime).iter(|| round(n, &upstreams))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_round);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+//! Tree hashing, from a single leaf up to a million, serially and on the rayon worker pool.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+
+use foxglove::trees::{TreeBuilder, TreeShape, hash_tree};
+
+fn digests(n: u32) -> Vec<[u8; 32]> {
+    (0 .. n).map(|i| {
+        let mut digest = [0; 32];
+        digest[.. 4].copy_from_slice(&i.to_le_bytes());
+        digest
+    }).collect()
+}
+
+fn bench_hash_tree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash_tree");
+    for n in [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000] {
+        if n >= 100_000 {
+            group.sample_size(10);
+        }
+        let digests = digests(n);
+        group.throughput(Throughput::Elements(n.into()));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &digests, |b, digests| {
+            b.iter(|| hash_tree(digests, TreeShape::Duplicate))
+        });
+    }
+    group.finish();
+}
+
+fn bench_tree_builder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree_builder");
+    for n in [1, 100, 10_000, 1_000_000] {
+        if n >= 100_000 {
+            group.sample_size(10);
+        }
+        let digests = digests(n);
+        group.throughput(Throughput::Elements(n.into()));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &digests, |b, digests| {
+            b.iter(|| digests.iter().copied().collect::<TreeBuilder>().finish())
+        });
+    }
+    group.finish();
+}
+
+/// What the aggregator runs when a batch is flushed, its digests having been pushed as they came.
+fn bench_tree_builder_finish_parallel(c: &mut Criterion) {
+    let serial = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+
+    let mut group = c.benchmark_group("tree_builder_finish_parallel");
+    group.sample_size(10);
+    for n in [1 << 14, 1 << 17, 1 << 20] {
+        let builder: TreeBuilder = digests(n).into_iter().collect();
+        group.throughput(Throughput::Elements(n.into()));
+        group.bench_with_input(BenchmarkId::new("serial", n), &builder, |b, builder| {
+            b.iter_batched(|| builder.clone(), |builder| serial.install(|| builder.finish()), BatchSize::LargeInput)
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", n), &builder, |b, builder| {
+            b.iter_batched(|| builder.clone(), TreeBuilder::finish, BatchSize::LargeInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_hash_tree, bench_tree_builder, bench_tree_builder_finish_parallel);
+criterion_main!(benches);
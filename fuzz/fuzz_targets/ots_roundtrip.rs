@@ -0,0 +1,21 @@
+//! Any proof that deserializes must serialize to bytes that deserialize back to the same proof,
+//! and serializing that again must give the same bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use foxglove::ots::{Limits, Timestamp};
+
+fuzz_target!(|data: &[u8]| {
+    let limits = Limits::default();
+    if let Ok(stamp) = Timestamp::deserialize(data, limits) {
+        let serialized = stamp.serialize();
+        // Padded varints are shortened, and nothing else changes length.
+        assert!(serialized.len() <= data.len());
+
+        let roundtripped = Timestamp::deserialize(&serialized, limits).expect("serialized proof deserializes");
+        assert_eq!(roundtripped, stamp);
+        assert_eq!(roundtripped.serialize(), serialized);
+    }
+});
@@ -1,19 +1,24 @@
 use std::convert::Infallible;
+use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 
 use bitcoin_hashes::Sha256;
-use rand;
-use reqwest::{StatusCode, Url};
+use hyper::body::Bytes;
+use reqwest::StatusCode;
 
-use tokio;
-
-use crate::trees::{Op, hash_tree};
+use crate::metrics::{self, METRICS};
+use crate::ots;
+use crate::trees::{Op, TreeBuilder, TreeShape};
+use crate::upstream::Upstreams;
 
 #[derive(Debug)]
 pub struct LinearTimestamp {
     nonce: [u8; 8],
     ops: Vec<Op>,
-    proof: Vec<u8>,
+
+    /// Proofs for the tip digest, one per upstream that answered.
+    proofs: Vec<Bytes>,
 }
 
 impl LinearTimestamp {
@@ -42,7 +47,15 @@ impl LinearTimestamp {
             }
         }
 
-        r.extend_from_slice(&self.proof);
+        // With more than one upstream proof the tip digest forks, with every branch but the last
+        // prefixed by the fork marker.
+        if let Some((last, rest)) = self.proofs.split_last() {
+            for proof in rest {
+                r.push(0xff); // fork
+                r.extend_from_slice(proof);
+            }
+            r.extend_from_slice(last);
+        }
 
         r.into()
     }
@@ -54,95 +67,285 @@ pub enum StampRequestError {
     Upstream(#[from] reqwest::Error),
 
     #[error("upstream aggregator returned bad status code: {0}")]
-    BadStatus(StatusCode)
+    BadStatus(StatusCode),
+
+    #[error("upstream aggregator returned invalid proof: {0}")]
+    InvalidProof(#[from] ots::DeserializeError),
+
+    #[error("upstream aggregator returned proof with an unexpected attestation or unsupported op: {0}")]
+    RejectedProof(ots::VerifyError),
+
+    #[error("upstream aggregator did not reply before the batch deadline")]
+    DeadlineExceeded,
+
+    #[error("circuit breakers are open for too many upstream aggregators")]
+    CircuitOpen,
+
+    #[error("only {successes} upstream aggregators succeeded; quorum is {quorum}")]
+    NoQuorum {
+        successes: usize,
+        quorum: usize,
+    },
+}
+
+impl StampRequestError {
+    /// Short, stable name of the error variant, for metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Upstream(_) => "upstream",
+            Self::BadStatus(_) => "bad_status",
+            Self::InvalidProof(_) => "invalid_proof",
+            Self::RejectedProof(_) => "rejected_proof",
+            Self::DeadlineExceeded => "deadline_exceeded",
+            Self::CircuitOpen => "circuit_open",
+            Self::NoQuorum { .. } => "no_quorum",
+        }
+    }
+}
+
+/// Identifies a batch in our logs, so that its failure is logged once rather than per request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchId(u64);
+
+impl BatchId {
+    fn new() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl fmt::Display for BatchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Failure of a batch, shared by every request in it.
+#[derive(Debug, Clone)]
+pub struct BatchError {
+    pub batch_id: BatchId,
+    pub error: Arc<StampRequestError>,
 }
 
 #[derive(Debug)]
 pub struct StampRequest {
     nonce: [u8; 8],
     digest: [u8; 32],
-    reply: tokio::sync::oneshot::Sender<Result<LinearTimestamp, Arc<StampRequestError>>>,
+    reply: tokio::sync::oneshot::Sender<Result<LinearTimestamp, BatchError>>,
 }
 
 impl StampRequest {
-    pub fn new(digest: &[u8]) -> (Self, tokio::sync::oneshot::Receiver<Result<LinearTimestamp, Arc<StampRequestError>>>) {
+    pub fn new(digest: &[u8]) -> (Self, tokio::sync::oneshot::Receiver<Result<LinearTimestamp, BatchError>>) {
         let (sender, receiver) = tokio::sync::oneshot::channel();
 
         let nonce: [u8; 8] = rand::random();
         (Self {
-            digest: Sha256::hash_byte_chunks(&[digest, &nonce]).to_byte_array(),
+            digest: Sha256::hash_byte_chunks([digest, &nonce]).to_byte_array(),
             nonce,
             reply: sender,
          },
          receiver)
     }
-}
-
-pub fn aggregate_requests(requests: Vec<StampRequest>, upstream_url: Url) {
-    let digests: Vec<[u8; 32]> = requests.iter().map(|req| req.digest).collect();
-
-    let (ops, tip_digest) = hash_tree(&digests);
 
-    let client = reqwest::blocking::Client::new();
+    /// The digest committed to in the tree: the submitted digest hashed with the nonce.
+    pub fn digest(&self) -> [u8; 32] {
+        self.digest
+    }
+}
 
-    match (|| -> Result<_, StampRequestError> {
-        let response = client.post(upstream_url)
-                             .header("User-Agent", concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION")))
-                             .body(Vec::from(tip_digest))
-                             .timeout(std::time::Duration::from_secs(2))
-                             .send()?;
-        if response.status() == StatusCode::OK {
-            let proof = response.bytes()?;
-            log::debug!("got {} bytes of proof from upstream", proof.len());
-            Ok(proof)
-        } else {
-            Err(StampRequestError::BadStatus(response.status()))
-        }
-    })() {
-        Ok(proof) => {
-            for (request, ops) in requests.into_iter().zip(ops.into_iter()) {
+/// Submits a batch, whose tree already has the digest of each request pushed in order.
+pub async fn aggregate_requests(requests: Vec<StampRequest>, tree: TreeBuilder, upstreams: &Upstreams) {
+    assert_eq!(requests.len(), tree.len());
+    let batch_id = BatchId::new();
+
+    // The tree is hashed as requests arrive; all that's left is the right edge and the paths.
+    let (ops, tip_digest) = tokio::task::spawn_blocking(move || tree.finish())
+                                       .await
+                                       .expect("TreeBuilder::finish does not panic");
+    // Each level adds a hash to a path, and the first digest's path goes through every level.
+    let depth = ops.first().map_or(0, |path| path.iter().filter(|op| **op == Op::Sha256).count());
+    METRICS.tree_depth.observe(depth as f64);
+
+    match upstreams.submit(tip_digest).await {
+        Ok(proofs) => {
+            for (request, ops) in requests.into_iter().zip(ops) {
                 let stamp = LinearTimestamp {
                     nonce: request.nonce,
                     ops,
-                    proof: proof.clone().into(),
+                    proofs: proofs.clone(),
                 };
 
                 let _ = request.reply.send(Ok(stamp));
             }
         }
         Err(err) => {
-            log::error!("{}", std::error::Report::new(&err).pretty(true));
-            let err = Arc::new(err);
+            log::error!("batch {} of {} requests failed: {}",
+                        batch_id, requests.len(), std::error::Report::new(&err).pretty(true));
+            METRICS.batch_failures.inc(err.name());
+            let err = BatchError {
+                batch_id,
+                error: Arc::new(err),
+            };
             for request in requests.into_iter() {
-                let _ = request.reply.send(Err(Arc::clone(&err)));
+                let _ = request.reply.send(Err(err.clone()));
             }
         },
     }
 }
 
+/// How requests are grouped into batches.
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// Batching period, used as is until the upstream latency is known.
+    pub period: Duration,
+
+    /// Bounds on the batching period, which otherwise follows the upstream latency.
+    pub min_period: Duration,
+    pub max_period: Duration,
+
+    /// Flush a batch early once it has this many requests.
+    pub target_batch_size: Option<usize>,
+
+    /// Maximum number of requests in a single batch, and what to do with requests beyond that.
+    pub max_batch_size: Option<usize>,
+    pub overflow: BatchOverflow,
+
+    /// Shape of each batch's merkle tree.
+    pub tree_shape: TreeShape,
+
+    /// Maximum number of batches waiting on upstream at once.
+    pub max_inflight_batches: usize,
+}
+
+/// What to do with requests beyond the maximum batch size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BatchOverflow {
+    /// Submit full batches immediately, as separate trees.
+    Split,
+
+    /// Leave the extra requests queued for the next batch.
+    Carry,
+}
+
+impl BatchConfig {
+    /// Batching period given the current upstream latency.
+    ///
+    /// There is no point in flushing batches faster than upstream answers them, while a fast
+    /// upstream lets us flush sooner than the configured period.
+    pub fn period(&self, upstream_latency: Option<Duration>) -> Duration {
+        upstream_latency.unwrap_or(self.period).clamp(self.min_period, self.max_period)
+    }
+}
+
+#[derive(Debug)]
+enum FlushReason {
+    Timer(Duration),
+    BatchFull,
+    Split,
+    Closed,
+}
+
+impl fmt::Display for FlushReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlushReason::Timer(period) => write!(f, "period of {:?} elapsed", period),
+            FlushReason::BatchFull => write!(f, "batch full"),
+            FlushReason::Split => write!(f, "maximum batch size reached"),
+            FlushReason::Closed => write!(f, "request channel closed"),
+        }
+    }
+}
+
 pub async fn aggregator_task(
     mut request_mpsc: tokio::sync::mpsc::Receiver<StampRequest>,
-    period: tokio::time::Duration,
-    upstream_url: Url,
+    config: BatchConfig,
+    upstreams: Arc<Upstreams>,
 ) -> Result<(), Infallible>
 {
-    let mut interval = tokio::time::interval(period);
-
-    while !request_mpsc.is_closed() {
-        interval.tick().await;
+    let inflight_batches = Arc::new(tokio::sync::Semaphore::new(config.max_inflight_batches));
+
+    let mut requests: Vec<StampRequest> = vec![];
+    let mut tree = TreeBuilder::new(config.tree_shape);
+
+    // The batch is flushed once its period has elapsed since the first request arrived. If the
+    // batch is split, the remainder is still flushed at the end of that period.
+    let mut flush_at = None;
+    let mut period = config.period;
+
+    loop {
+        let full = config.max_batch_size.is_some_and(|max| requests.len() >= max);
+        let reason = tokio::select! {
+            request = request_mpsc.recv(), if !full => {
+                match request {
+                    Some(request) => {
+                        if flush_at.is_none() {
+                            period = config.period(upstreams.latency());
+                            flush_at = Some(tokio::time::Instant::now() + period);
+                        }
+                        tree.push(request.digest);
+                        requests.push(request);
+                        if config.target_batch_size.is_some_and(|target| requests.len() >= target) {
+                            FlushReason::BatchFull
+                        } else if config.overflow == BatchOverflow::Split
+                               && config.max_batch_size.is_some_and(|max| requests.len() >= max)
+                        {
+                            FlushReason::Split
+                        } else {
+                            continue;
+                        }
+                    },
+                    None if requests.is_empty() => break,
+                    None => FlushReason::Closed,
+                }
+            },
+            _ = tokio::time::sleep_until(flush_at.unwrap_or_else(tokio::time::Instant::now)), if flush_at.is_some() => {
+                FlushReason::Timer(period)
+            },
+        };
 
-        let mut requests: Vec<StampRequest> = vec![];
-        while let Ok(request) = request_mpsc.try_recv() {
-            requests.push(request);
+        match reason {
+            FlushReason::Split => metrics::inc(&METRICS.batch_splits),
+            _ => flush_at = None,
+        }
+        if requests.is_empty() {
+            continue;
         }
 
-        if requests.len() > 0 {
-            log::info!("got {} requests", requests.len());
-            let upstream_url = upstream_url.clone();
-            let _ = tokio::task::spawn_blocking(move || aggregate_requests(requests, upstream_url));
+        if full && !request_mpsc.is_empty() {
+            log::info!("carrying {} requests over to the next batch", request_mpsc.len());
+            metrics::inc(&METRICS.batch_carryovers);
         }
+
+        log::info!("flushing {} requests: {}", requests.len(), reason);
+        METRICS.batch_size.observe(requests.len() as f64);
+        let requests = std::mem::take(&mut requests);
+        let tree = std::mem::replace(&mut tree, TreeBuilder::new(config.tree_shape));
+
+        // Wait for a slot if too many batches are already waiting on upstream; meanwhile new
+        // requests queue up in the channel.
+        let permit = match Arc::clone(&inflight_batches).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::warn!("{} batches already in flight; waiting", config.max_inflight_batches);
+                Arc::clone(&inflight_batches).acquire_owned().await.expect("semaphore is never closed")
+            },
+        };
+
+        let upstreams = Arc::clone(&upstreams);
+        metrics::inc(&METRICS.inflight_batches);
+        tokio::task::spawn(async move {
+            aggregate_requests(requests, tree, &upstreams).await;
+            metrics::dec(&METRICS.inflight_batches);
+            drop(permit);
+        });
     };
 
+    // All senders are gone, so we're shutting down; wait for batches still waiting on upstream.
+    let inflight = config.max_inflight_batches - inflight_batches.available_permits();
+    if inflight > 0 {
+        log::info!("waiting for {} in-flight batches", inflight);
+    }
+    let _ = inflight_batches.acquire_many(u32::try_from(config.max_inflight_batches).unwrap_or(u32::MAX)).await;
+
     Ok(())
 }
 
@@ -150,9 +353,38 @@ pub async fn aggregator_task(
 mod tests {
     use super::*;
 
+    use crate::mock_calendar::{MockCalendar, Reply};
+    use crate::upstream::UpstreamsConfig;
+
+    #[test]
+    fn test_linear_timestamp_serialize() {
+        let stamp = LinearTimestamp {
+            nonce: [0; 8],
+            ops: vec![Op::Append([1; 32]), Op::Sha256],
+            proofs: vec![Bytes::from_static(&[0x00, 0xaa]), Bytes::from_static(&[0x00, 0xbb]), Bytes::from_static(&[0x00, 0xcc])],
+        };
+
+        let mut prefix = vec![0xf0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0xf0, 32];
+        prefix.extend_from_slice(&[1; 32]);
+        prefix.push(0x08);
+
+        assert_eq!(stamp.serialize()[prefix.len() ..],
+                   [0xff, 0x00, 0xaa,
+                    0xff, 0x00, 0xbb,
+                    0x00, 0xcc]);
+
+        let stamp = LinearTimestamp {
+            proofs: vec![Bytes::from_static(&[0x00, 0xaa])],
+            ..stamp
+        };
+        assert_eq!(stamp.serialize()[.. prefix.len()], prefix[..]);
+        assert_eq!(stamp.serialize()[prefix.len() ..], [0x00, 0xaa]);
+    }
+
     #[tokio::test]
     async fn test_aggregate_requests() {
-        let url = Url::parse("https://a.pool.opentimestamps.org/digest").unwrap();
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
 
         let (sender, receiver) = tokio::sync::oneshot::channel();
         let req = StampRequest {
@@ -161,26 +393,169 @@ mod tests {
             reply: sender,
         };
 
-        let _ = tokio::task::spawn_blocking(move || aggregate_requests(vec![req], url));
+        let tree = [req.digest].into_iter().collect();
+        aggregate_requests(vec![req], tree, &upstreams).await;
+
+        let stamp = receiver.await.unwrap().unwrap();
+        assert_eq!(stamp.proofs, vec![calendar.proof()]);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_requests_upstream_failure() {
+        let calendar = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+
+        let (req, receiver) = StampRequest::new(&[0; 32]);
+        let tree = [req.digest].into_iter().collect();
+        aggregate_requests(vec![req], tree, &upstreams).await;
+
+        let err = receiver.await.unwrap().unwrap_err();
+        assert!(matches!(*err.error, StampRequestError::BadStatus(StatusCode::BAD_GATEWAY)));
+    }
+
+    fn batch_config(period: Duration) -> BatchConfig {
+        BatchConfig {
+            period,
+            min_period: period,
+            max_period: period,
+            target_batch_size: None,
+            max_batch_size: None,
+            overflow: BatchOverflow::Split,
+            tree_shape: TreeShape::Duplicate,
+            max_inflight_batches: 1,
+        }
+    }
+
+    #[test]
+    fn test_batch_period() {
+        let config = BatchConfig {
+            min_period: Duration::from_millis(10),
+            max_period: Duration::from_secs(1),
+            ..batch_config(Duration::from_millis(100))
+        };
+        assert_eq!(config.period(None), Duration::from_millis(100));
+        assert_eq!(config.period(Some(Duration::from_millis(50))), Duration::from_millis(50));
+        assert_eq!(config.period(Some(Duration::from_millis(1))), Duration::from_millis(10));
+        assert_eq!(config.period(Some(Duration::from_secs(5))), Duration::from_secs(1));
+
+        let config = batch_config(Duration::from_millis(100));
+        assert_eq!(config.period(Some(Duration::from_millis(50))), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_aggregator_batch_full() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Arc::new(Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap());
+
+        // The period is far longer than the test could take, so the batch has to be flushed early.
+        let config = BatchConfig {
+            target_batch_size: Some(2),
+            ..batch_config(Duration::from_secs(3600))
+        };
+        let (sender, request_mpsc) = tokio::sync::mpsc::channel(128);
+        let _task = tokio::task::spawn(aggregator_task(request_mpsc, config, upstreams));
+
+        let (req1, stamp_recv1) = StampRequest::new(&[0; 32]);
+        let (req2, stamp_recv2) = StampRequest::new(&[1; 32]);
+        sender.send(req1).await.unwrap();
+        sender.send(req2).await.unwrap();
+
+        stamp_recv1.await.unwrap().unwrap();
+        stamp_recv2.await.unwrap().unwrap();
+        assert_eq!(calendar.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_aggregator_split() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Arc::new(Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap());
+
+        let config = BatchConfig {
+            max_batch_size: Some(2),
+            overflow: BatchOverflow::Split,
+            tree_shape: TreeShape::Duplicate,
+            ..batch_config(Duration::from_millis(100))
+        };
+        let (sender, request_mpsc) = tokio::sync::mpsc::channel(128);
+        let _task = tokio::task::spawn(aggregator_task(request_mpsc, config, upstreams));
+
+        let mut stamp_recvs = vec![];
+        for i in 0 .. 5 {
+            let (req, stamp_recv) = StampRequest::new(&[i; 32]);
+            sender.send(req).await.unwrap();
+            stamp_recvs.push(stamp_recv);
+        }
+        for stamp_recv in stamp_recvs {
+            let stamp = stamp_recv.await.unwrap().unwrap();
+            assert!(stamp.ops.len() <= 2);
+        }
+
+        // Two full batches, plus the remaining request at the end of the period.
+        assert_eq!(calendar.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_aggregator_carry() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Arc::new(Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap());
+
+        let config = BatchConfig {
+            max_batch_size: Some(2),
+            overflow: BatchOverflow::Carry,
+            tree_shape: TreeShape::Duplicate,
+            ..batch_config(Duration::from_millis(50))
+        };
+        let (sender, request_mpsc) = tokio::sync::mpsc::channel(128);
+
+        let mut stamp_recvs = vec![];
+        for i in 0 .. 3 {
+            let (req, stamp_recv) = StampRequest::new(&[i; 32]);
+            sender.send(req).await.unwrap();
+            stamp_recvs.push(stamp_recv);
+        }
+        let _task = tokio::task::spawn(aggregator_task(request_mpsc, config, upstreams));
+
+        let start = tokio::time::Instant::now();
+        for stamp_recv in stamp_recvs {
+            stamp_recv.await.unwrap().unwrap();
+        }
+
+        // The third request had to wait for a second period.
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(calendar.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_aggregator_drains_on_close() {
+        let calendar = MockCalendar::start(Reply::Delay(Duration::from_millis(100))).await;
+        let upstreams = Arc::new(Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap());
+
+        // Closing the channel flushes the batch without waiting for the period, and the task
+        // only finishes once the batch has its proof.
+        let (sender, request_mpsc) = tokio::sync::mpsc::channel(128);
+        let task = tokio::task::spawn(aggregator_task(request_mpsc, batch_config(Duration::from_secs(60)), upstreams));
 
-        receiver.await.unwrap().unwrap();
+        let (req, mut stamp_recv) = StampRequest::new(&[0; 32]);
+        sender.send(req).await.unwrap();
+        drop(sender);
+
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap().unwrap();
+        stamp_recv.try_recv().unwrap().unwrap();
     }
 
     #[tokio::test]
     async fn test_aggregator() -> Result<(), Box<dyn std::error::Error>> {
-        let url = Url::parse("https://a.pool.opentimestamps.org/digest").unwrap();
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Arc::new(Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap());
 
         let period = std::time::Duration::from_millis(100);
         let (sender, request_mpsc) = tokio::sync::mpsc::channel(128);
-        let _task = aggregator_task(request_mpsc, period, url);
+        let _task = tokio::task::spawn(aggregator_task(request_mpsc, batch_config(period), upstreams));
 
-        let (req_reply, _stamp_recv) = tokio::sync::oneshot::channel();
-        sender.send(StampRequest {
-            nonce: [0; 8],
-            digest: [0; 32],
-            reply: req_reply,
-        }).await.unwrap();
+        let (req, stamp_recv) = StampRequest::new(&[0; 32]);
+        sender.send(req).await.unwrap();
 
+        stamp_recv.await?.unwrap();
         Ok(())
     }
 }
@@ -0,0 +1,14 @@
+#![feature(error_reporter)]
+
+pub mod aggregator;
+pub mod metrics;
+pub mod ots;
+pub mod proxy;
+pub mod ratelimit;
+pub mod rpc;
+pub mod upstream;
+
+pub mod trees;
+
+#[cfg(any(test, feature = "mock-calendar"))]
+pub mod mock_calendar;
@@ -1,35 +1,201 @@
 #![feature(error_reporter)]
 
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::num::NonZero;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap::error::ErrorKind;
 
 use hyper::server::conn::http1;
 use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
 use tokio::net::TcpListener;
-use reqwest::Url;
+use tokio::signal::unix::{SignalKind, signal};
+use reqwest::{StatusCode, Url};
+use reqwest::header::{HeaderName, HeaderValue};
 
-mod aggregator;
-mod rpc;
-
-mod trees;
+use foxglove::{aggregator, ots, rpc};
+use foxglove::aggregator::{BatchConfig, BatchOverflow};
+use foxglove::proxy::{CachePolicy, TimestampProxy};
+use foxglove::ratelimit::{IpRange, RateLimitConfig, RateLimiter};
+use foxglove::trees::TreeShape;
+use foxglove::upstream::{BreakerPolicy, ClientConfig, RetryPolicy, Upstream, UpstreamMode, UpstreamOrder, Upstreams,
+                          UpstreamsConfig};
 
 #[derive(Parser, Debug)]
 #[clap(version)]
 struct Args {
+    /// Batching period, in seconds
     #[arg(long, value_parser = parse_duration, default_value = "0.1")]
     period: Duration,
 
+    /// Lower bound on the batching period, which follows upstream latency; defaults to --period
+    #[arg(long, value_parser = parse_duration)]
+    min_period: Option<Duration>,
+
+    /// Upper bound on the batching period, which follows upstream latency; defaults to --period
+    #[arg(long, value_parser = parse_duration)]
+    max_period: Option<Duration>,
+
+    /// Flush a batch before the period is over once it has this many requests
+    #[arg(long)]
+    target_batch_size: Option<NonZero<usize>>,
+
+    /// Maximum number of requests in a single batch
+    #[arg(long)]
+    max_batch_size: Option<NonZero<usize>>,
+
+    /// What to do with requests beyond --max-batch-size
+    #[arg(long, value_enum, default_value = "split")]
+    batch_overflow: BatchOverflow,
+
+    /// What to do with the odd node at the end of a merkle tree level
+    #[arg(long, value_enum, default_value = "duplicate")]
+    tree_shape: TreeShape,
+
     #[arg(long, default_value = "127.0.0.1:3000")]
     bind: SocketAddr,
 
     #[arg(long, default_value = "256")]
     queue_depth: NonZero<usize>,
 
-    #[arg(value_parser = parse_url)]
-    upstream_url: Url,
+    /// Reject digest submissions with 503 once the queue has been full for this long, in
+    /// seconds; 0 rejects immediately. If unset, submissions wait until there is room.
+    #[arg(long, value_parser = parse_duration)]
+    queue_full_wait: Option<Duration>,
+
+    /// Upstream calendar(s) to submit tips to
+    #[arg(value_parser = parse_url, required = true)]
+    upstream_urls: Vec<Url>,
+
+    /// Submit each tip to every upstream at once, rather than failing over between them
+    #[arg(long)]
+    fanout: bool,
+
+    /// With --fanout, the number of upstreams that must succeed for a batch to succeed
+    #[arg(long, default_value = "1", requires = "fanout")]
+    quorum: NonZero<usize>,
+
+    /// URI of the calendar that proofs from each upstream must end in a pending attestation from,
+    /// given in the same order as the upstreams; defaults to the origin of the upstream URL
+    #[arg(long = "calendar-uri")]
+    calendar_uris: Vec<String>,
+
+    /// Order in which upstream calendars are tried when failing over
+    #[arg(long, value_enum, default_value = "health", conflicts_with = "fanout")]
+    upstream_order: UpstreamOrder,
+
+    /// Maximum number of attempts per upstream for each batch, including the first
+    #[arg(long, default_value = "3")]
+    retry_attempts: NonZero<u32>,
+
+    /// Backoff after the first failed attempt, doubling for each further attempt
+    #[arg(long, value_parser = parse_nonzero_duration, default_value = "0.1")]
+    retry_base_backoff: Duration,
+
+    /// Maximum backoff between attempts
+    #[arg(long, value_parser = parse_nonzero_duration, default_value = "1")]
+    retry_max_backoff: Duration,
+
+    /// Fraction of each backoff that is randomized, from 0 to 1
+    #[arg(long, value_parser = parse_fraction, default_value = "0.5")]
+    retry_jitter: f64,
+
+    /// Upstream status codes that are retried; transport errors are always retried
+    #[arg(long, value_parser = parse_status, value_delimiter = ',', default_value = "502,503,504")]
+    retry_status: Vec<StatusCode>,
+
+    /// Total time allowed to get proofs for a batch, including all retries
+    #[arg(long, value_parser = parse_nonzero_duration, default_value = "5")]
+    batch_deadline: Duration,
+
+    /// Timeout for each upstream request
+    #[arg(long, value_parser = parse_nonzero_duration, default_value = "2")]
+    upstream_timeout: Duration,
+
+    /// Timeout for connecting to an upstream
+    #[arg(long, value_parser = parse_nonzero_duration, default_value = "1")]
+    upstream_connect_timeout: Duration,
+
+    /// Extra header to send with every upstream request, as "Name: value"; may be repeated
+    #[arg(long = "upstream-header", value_parser = parse_header)]
+    upstream_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// PEM file of CA certificates to trust for upstreams, in addition to the system roots; may be
+    /// repeated
+    #[arg(long = "upstream-ca-cert")]
+    upstream_ca_certs: Vec<PathBuf>,
+
+    /// PEM file with the client certificate chain to present to upstreams
+    #[arg(long, requires = "upstream_client_key")]
+    upstream_client_cert: Option<PathBuf>,
+
+    /// PEM file with the PKCS#8 private key for --upstream-client-cert
+    #[arg(long, requires = "upstream_client_cert")]
+    upstream_client_key: Option<PathBuf>,
+
+    /// Number of consecutive failed batches after which an upstream's circuit breaker opens
+    #[arg(long, default_value = "5")]
+    breaker_threshold: NonZero<u32>,
+
+    /// How long an upstream's circuit breaker stays open before a probe is let through
+    #[arg(long, value_parser = parse_nonzero_duration, default_value = "10")]
+    breaker_cooldown: Duration,
+
+    /// Maximum number of batches waiting on upstream at once
+    #[arg(long, default_value = "16")]
+    max_inflight_batches: NonZero<usize>,
+
+    /// Maximum size in bytes of a proof returned by an upstream
+    #[arg(long, default_value = "10000")]
+    max_proof_size: usize,
+
+    /// Maximum number of nested operations in a proof returned by an upstream
+    #[arg(long, default_value = "256")]
+    max_proof_depth: usize,
+
+    /// How long pending timestamps from upgrade requests are cached, in seconds
+    #[arg(long, value_parser = parse_duration, default_value = "10")]
+    timestamp_cache_ttl: Duration,
+
+    /// How long Bitcoin-attested timestamps from upgrade requests are cached, in seconds
+    #[arg(long, value_parser = parse_duration, default_value = "86400")]
+    timestamp_cache_complete_ttl: Duration,
+
+    /// Maximum number of replies to timestamp upgrade requests that are cached
+    #[arg(long, default_value = "10000")]
+    timestamp_cache_size: usize,
+
+    /// Sustained digest submissions per second allowed from each client IP, or IPv6 /64; unlimited if unset
+    #[arg(long)]
+    rate_limit: Option<f64>,
+
+    /// Digest submissions a client IP may burst to after being idle
+    #[arg(long, default_value = "20", requires = "rate_limit")]
+    rate_limit_burst: NonZero<u32>,
+
+    /// Address or CIDR range of a proxy whose X-Forwarded-For header is trusted
+    #[arg(long = "trusted-proxy", value_name = "RANGE", requires = "rate_limit")]
+    trusted_proxies: Vec<IpRange>,
+
+    /// Address or CIDR range of clients that are never rate limited
+    #[arg(long = "rate-limit-allow", value_name = "RANGE", requires = "rate_limit")]
+    rate_limit_allowlist: Vec<IpRange>,
+
+    /// Report not ready once batches have been failing upstream for this long, in seconds
+    #[arg(long, value_parser = parse_duration, default_value = "60")]
+    unready_after_failing: Duration,
+
+    /// Report not ready once this fraction of the request queue is in use
+    #[arg(long, value_parser = parse_fraction, default_value = "0.9")]
+    unready_queue_fill: f64,
+
+    /// How long to wait for open connections and in-flight batches on shutdown, in seconds
+    #[arg(long, value_parser = parse_duration, default_value = "30")]
+    shutdown_timeout: Duration,
 
     /// Human readable name for us
     #[arg(long)]
@@ -40,9 +206,37 @@ struct Args {
     upstream_calendar_name: Option<String>,
 }
 
-fn parse_duration(arg: &str) -> Result<Duration, std::num::ParseFloatError> {
-    let seconds = arg.parse()?;
-    Ok(Duration::from_secs_f64(seconds))
+fn parse_duration(arg: &str) -> Result<Duration, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let seconds: f64 = arg.parse()?;
+    Ok(Duration::try_from_secs_f64(seconds)?)
+}
+
+/// Parses a duration that must not be zero, such as a timeout that would otherwise always expire.
+fn parse_nonzero_duration(arg: &str) -> Result<Duration, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let duration = parse_duration(arg)?;
+    if duration.is_zero() {
+        Err("must be greater than 0".into())
+    } else {
+        Ok(duration)
+    }
+}
+
+fn parse_fraction(arg: &str) -> Result<f64, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let fraction: f64 = arg.parse()?;
+    if (0.0 ..= 1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err("must be between 0 and 1".into())
+    }
+}
+
+fn parse_status(arg: &str) -> Result<StatusCode, Box<dyn std::error::Error + Send + Sync + 'static>> {
+    Ok(StatusCode::from_bytes(arg.as_bytes())?)
+}
+
+fn parse_header(arg: &str) -> Result<(HeaderName, HeaderValue), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let (name, value) = arg.split_once(':').ok_or("expected \"Name: value\"")?;
+    Ok((name.trim().parse()?, value.trim().parse()?))
 }
 
 fn parse_url(arg: &str) -> Result<Url, Box<dyn std::error::Error + Send + Sync + 'static>> {
@@ -56,40 +250,166 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let args = Args::parse();
 
+    if args.min_period.unwrap_or(args.period) > args.max_period.unwrap_or(args.period) {
+        Args::command().error(ErrorKind::ArgumentConflict,
+                              "minimum period is larger than maximum period")
+                       .exit();
+    }
+
+    if args.calendar_uris.len() > args.upstream_urls.len() {
+        Args::command().error(ErrorKind::TooManyValues,
+                              "more calendar URIs than upstreams")
+                       .exit();
+    }
+
+    if args.quorum.get() > args.upstream_urls.len() {
+        Args::command().error(ErrorKind::ValueValidation,
+                              format!("quorum of {} is larger than the number of upstreams", args.quorum))
+                       .exit();
+    }
+
+    if args.rate_limit.is_some_and(|rate| !(rate > 0.0 && rate.is_finite())) {
+        Args::command().error(ErrorKind::ValueValidation,
+                              "rate limit must be a positive number of requests per second")
+                       .exit();
+    }
+
     let (request_sender, request_receiver) = tokio::sync::mpsc::channel(args.queue_depth.into());
 
-    tokio::task::spawn(aggregator::aggregator_task(request_receiver, args.period, args.upstream_url.clone()));
+    let upstream_mode = if args.fanout {
+        UpstreamMode::Fanout { quorum: args.quorum.into() }
+    } else {
+        UpstreamMode::Failover(args.upstream_order)
+    };
+    let proof_limits = ots::Limits {
+        max_size: args.max_proof_size,
+        max_depth: args.max_proof_depth,
+    };
+    let upstreams = args.upstream_urls.iter().enumerate().map(|(i, url)| {
+        Upstream::new(url.clone(), args.calendar_uris.get(i).cloned())
+    });
+    let upstreams_config = UpstreamsConfig {
+        mode: upstream_mode,
+        limits: proof_limits,
+        retry: RetryPolicy {
+            max_attempts: args.retry_attempts.into(),
+            base_backoff: args.retry_base_backoff,
+            max_backoff: args.retry_max_backoff,
+            jitter: args.retry_jitter,
+            retryable_statuses: args.retry_status.clone(),
+            deadline: args.batch_deadline,
+        },
+        breaker: BreakerPolicy {
+            threshold: args.breaker_threshold.into(),
+            cooldown: args.breaker_cooldown,
+        },
+        client: ClientConfig {
+            timeout: args.upstream_timeout,
+            connect_timeout: args.upstream_connect_timeout,
+            headers: args.upstream_headers.iter().cloned().collect(),
+            ca_certs: args.upstream_ca_certs.clone(),
+            client_cert: args.upstream_client_cert.clone().zip(args.upstream_client_key.clone()),
+        },
+    };
+    let upstreams = match Upstreams::new(upstreams, upstreams_config) {
+        Ok(upstreams) => Arc::new(upstreams),
+        Err(err) => Args::command().error(ErrorKind::InvalidValue, err).exit(),
+    };
+
+    let batch_config = BatchConfig {
+        period: args.period,
+        min_period: args.min_period.unwrap_or(args.period),
+        max_period: args.max_period.unwrap_or(args.period),
+        target_batch_size: args.target_batch_size.map(Into::into),
+        max_batch_size: args.max_batch_size.map(Into::into),
+        overflow: args.batch_overflow,
+        tree_shape: args.tree_shape,
+        max_inflight_batches: args.max_inflight_batches.into(),
+    };
+    let server_info = Arc::new(rpc::ServerInfo {
+        our_name: args.our_name.clone().unwrap_or(args.bind.to_string()),
+        upstream_calendar_name: args.upstream_calendar_name.clone().unwrap_or(args.upstream_urls[0].to_string()),
+        batch_config: batch_config.clone(),
+        started: Instant::now(),
+        readiness: rpc::Readiness {
+            max_failing: args.unready_after_failing,
+            max_queue_fill: args.unready_queue_fill,
+        },
+        queue_full_wait: args.queue_full_wait,
+    });
+    let rate_limiter = args.rate_limit.map(|rate| Arc::new(RateLimiter::new(RateLimitConfig {
+        rate,
+        burst: args.rate_limit_burst.get().into(),
+        trusted_proxies: args.trusted_proxies.clone(),
+        allowlist: args.rate_limit_allowlist.clone(),
+    })));
+    let timestamp_proxy = Arc::new(TimestampProxy::new(CachePolicy {
+        capacity: args.timestamp_cache_size,
+        pending_ttl: args.timestamp_cache_ttl,
+        complete_ttl: args.timestamp_cache_complete_ttl,
+    }));
+    let aggregator = tokio::task::spawn(aggregator::aggregator_task(request_receiver, batch_config, Arc::clone(&upstreams)));
 
     // We create a TcpListener and bind it
     let listener = TcpListener::bind(args.bind).await?;
 
     log::info!("listening on {}", args.bind);
 
-    // We start a loop to continuously accept incoming connections
-    loop {
-        let our_name = args.our_name.clone().unwrap_or(args.bind.to_string());
-        let upstream_calendar_name = args.upstream_calendar_name.clone().unwrap_or(args.upstream_url.to_string());
+    let graceful = GracefulShutdown::new();
+    let mut shutdown = std::pin::pin!(shutdown_signal());
 
-        let (stream, _) = listener.accept().await?;
+    // We start a loop to continuously accept incoming connections, until asked to shut down
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
 
         // Use an adapter to access something implementing `tokio::io` traits as if they implement
         // `hyper::rt` IO traits.
         let io = TokioIo::new(stream);
 
+        // Finally, we bind the incoming connection to our RPC service
+        let connection = http1::Builder::new()
+            .serve_connection(io, rpc::RPCService::new(
+                    request_sender.clone(),
+                    Arc::clone(&upstreams),
+                    Arc::clone(&timestamp_proxy),
+                    rate_limiter.clone(),
+                    remote_addr,
+                    Arc::clone(&server_info),
+                    ));
+        let connection = graceful.watch(connection);
+
         // Spawn a tokio task to serve multiple connections concurrently
-        let request_sender = request_sender.clone();
         tokio::task::spawn(async move {
-            // Finally, we bind the incoming connection to our RPC service
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(io, rpc::RPCService::new(
-                        request_sender,
-                        our_name.clone(),
-                        upstream_calendar_name.clone(),
-                        ))
-                .await
-            {
+            if let Err(err) = connection.await {
                 log::debug!("Error serving connection: {}", std::error::Report::new(err).pretty(true));
             }
         });
     }
+
+    // Stop accepting connections, let open ones finish their requests, then let the aggregator
+    // flush what is left in the channel once the last request sender is gone.
+    drop(listener);
+    drop(request_sender);
+    let drain = async {
+        graceful.shutdown().await;
+        log::info!("all connections closed; waiting for the aggregator to finish");
+        aggregator.await.expect("aggregator task does not panic")
+    };
+    match tokio::time::timeout(args.shutdown_timeout, drain).await {
+        Ok(_) => log::info!("shut down cleanly"),
+        Err(_) => log::warn!("gave up waiting for connections and batches after {:?}", args.shutdown_timeout),
+    }
+    Ok(())
+}
+
+/// Waits for SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("SIGTERM handler can be installed");
+    tokio::select! {
+        _ = sigterm.recv() => log::info!("got SIGTERM; shutting down"),
+        _ = tokio::signal::ctrl_c() => log::info!("got SIGINT; shutting down"),
+    }
 }
@@ -0,0 +1,262 @@
+//! Process-wide metrics, exposed in the Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A histogram with fixed bucket upper bounds.
+#[derive(Debug)]
+pub struct Histogram<const N: usize> {
+    bounds: [f64; N],
+    counts: [AtomicU64; N],
+    count: AtomicU64,
+
+    /// Sum of all observations, as f64 bits.
+    sum: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    pub const fn new(bounds: [f64; N]) -> Self {
+        Self {
+            bounds,
+            counts: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+            Some((f64::from_bits(sum) + value).to_bits())
+        });
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+        writeln!(out, "# HELP {name} {help}")?;
+        writeln!(out, "# TYPE {name} histogram")?;
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}")?;
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}")?;
+        writeln!(out, "{name}_sum {}", f64::from_bits(self.sum.load(Ordering::Relaxed)))?;
+        writeln!(out, "{name}_count {count}")
+    }
+}
+
+/// Counters keyed by a set of label values.
+#[derive(Debug)]
+pub struct Counters<K>(Mutex<BTreeMap<K, u64>>);
+
+impl<K: Ord> Counters<K> {
+    pub const fn new() -> Self {
+        Self(Mutex::new(BTreeMap::new()))
+    }
+
+    pub fn inc(&self, key: K) {
+        *self.0.lock().unwrap().entry(key).or_default() += 1;
+    }
+}
+
+impl<K: Ord> Default for Counters<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    /// HTTP requests served, by route and status code.
+    pub http_requests: Counters<(&'static str, u16)>,
+
+    /// Digest submissions rejected by the rate limiter.
+    pub rate_limited: AtomicU64,
+
+    /// Digest submissions rejected because the request queue was full.
+    pub queue_full: AtomicU64,
+
+    /// Number of requests in each batch submitted upstream.
+    pub batch_size: Histogram<7>,
+
+    /// Batches flushed early because they reached the maximum batch size.
+    pub batch_splits: AtomicU64,
+
+    /// Batches flushed with requests left over for the next batch.
+    pub batch_carryovers: AtomicU64,
+
+    /// Batches that failed, by error.
+    pub batch_failures: Counters<&'static str>,
+
+    /// Batches currently being hashed or submitted upstream.
+    pub inflight_batches: AtomicU64,
+
+    /// Depth of the merkle tree of each batch.
+    pub tree_depth: Histogram<6>,
+
+    /// Time taken to submit a batch upstream, in seconds, including retries.
+    pub upstream_latency: Histogram<10>,
+
+    /// Failed upstream submissions, by upstream and error.
+    pub upstream_errors: Counters<(String, &'static str)>,
+
+    /// Timestamp upgrade requests answered from the cache, or not.
+    pub timestamp_cache_hits: AtomicU64,
+    pub timestamp_cache_misses: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    http_requests: Counters::new(),
+    rate_limited: AtomicU64::new(0),
+    queue_full: AtomicU64::new(0),
+    batch_size: Histogram::new([1.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0]),
+    batch_splits: AtomicU64::new(0),
+    batch_carryovers: AtomicU64::new(0),
+    batch_failures: Counters::new(),
+    inflight_batches: AtomicU64::new(0),
+    tree_depth: Histogram::new([0.0, 4.0, 8.0, 12.0, 16.0, 20.0]),
+    upstream_latency: Histogram::new([0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+    upstream_errors: Counters::new(),
+    timestamp_cache_hits: AtomicU64::new(0),
+    timestamp_cache_misses: AtomicU64::new(0),
+};
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn dec(gauge: &AtomicU64) {
+    gauge.fetch_sub(1, Ordering::Relaxed);
+}
+
+fn render_value(out: &mut String, name: &str, kind: &str, help: &str, value: u64) -> fmt::Result {
+    writeln!(out, "# HELP {name} {help}")?;
+    writeln!(out, "# TYPE {name} {kind}")?;
+    writeln!(out, "{name} {value}")
+}
+
+/// Escapes a label value.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Renders all metrics in the Prometheus text exposition format.
+    ///
+    /// The queue depth lives in the request channel rather than here, so is passed in.
+    pub fn render(&self, queue_depth: usize) -> String {
+        let mut out = String::new();
+        self.render_into(&mut out, queue_depth).expect("writing to a String does not fail");
+        out
+    }
+
+    fn render_into(&self, out: &mut String, queue_depth: usize) -> fmt::Result {
+        writeln!(out, "# HELP foxglove_http_requests_total HTTP requests served.")?;
+        writeln!(out, "# TYPE foxglove_http_requests_total counter")?;
+        for ((route, status), count) in self.http_requests.0.lock().unwrap().iter() {
+            writeln!(out, "foxglove_http_requests_total{{route=\"{route}\",status=\"{status}\"}} {count}")?;
+        }
+
+        render_value(out, "foxglove_rate_limited_total", "counter",
+                     "Digest submissions rejected by the rate limiter.",
+                     self.rate_limited.load(Ordering::Relaxed))?;
+        render_value(out, "foxglove_queue_full_total", "counter",
+                     "Digest submissions rejected because the request queue was full.",
+                     self.queue_full.load(Ordering::Relaxed))?;
+        render_value(out, "foxglove_queue_depth", "gauge",
+                     "Timestamp requests waiting to be batched.", queue_depth as u64)?;
+        self.batch_size.render(out, "foxglove_batch_size", "Number of requests in each batch.")?;
+        render_value(out, "foxglove_batch_splits_total", "counter",
+                     "Batches flushed early because they reached the maximum batch size.",
+                     self.batch_splits.load(Ordering::Relaxed))?;
+        render_value(out, "foxglove_batch_carryovers_total", "counter",
+                     "Batches flushed with requests left over for the next batch.",
+                     self.batch_carryovers.load(Ordering::Relaxed))?;
+
+        writeln!(out, "# HELP foxglove_batch_failures_total Batches that failed.")?;
+        writeln!(out, "# TYPE foxglove_batch_failures_total counter")?;
+        for (error, count) in self.batch_failures.0.lock().unwrap().iter() {
+            writeln!(out, "foxglove_batch_failures_total{{error=\"{error}\"}} {count}")?;
+        }
+
+        render_value(out, "foxglove_inflight_batches", "gauge",
+                     "Batches currently being hashed or submitted upstream.",
+                     self.inflight_batches.load(Ordering::Relaxed))?;
+        self.tree_depth.render(out, "foxglove_tree_depth", "Depth of the merkle tree of each batch.")?;
+        self.upstream_latency.render(out, "foxglove_upstream_latency_seconds",
+                                     "Time taken to submit a batch upstream, including retries.")?;
+
+        writeln!(out, "# HELP foxglove_upstream_errors_total Failed upstream submissions.")?;
+        writeln!(out, "# TYPE foxglove_upstream_errors_total counter")?;
+        for ((upstream, error), count) in self.upstream_errors.0.lock().unwrap().iter() {
+            writeln!(out, "foxglove_upstream_errors_total{{upstream=\"{}\",error=\"{error}\"}} {count}", label(upstream))?;
+        }
+
+        render_value(out, "foxglove_timestamp_cache_hits_total", "counter",
+                     "Timestamp upgrade requests answered from the cache.",
+                     self.timestamp_cache_hits.load(Ordering::Relaxed))?;
+        render_value(out, "foxglove_timestamp_cache_misses_total", "counter",
+                     "Timestamp upgrade requests forwarded upstream.",
+                     self.timestamp_cache_misses.load(Ordering::Relaxed))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_render() {
+        let histogram = Histogram::new([1.0, 10.0]);
+        histogram.observe(0.5);
+        histogram.observe(5.0);
+        histogram.observe(50.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out, "test", "Test histogram.").unwrap();
+        assert_eq!(out,
+"# HELP test Test histogram.
+# TYPE test histogram
+test_bucket{le=\"1\"} 1
+test_bucket{le=\"10\"} 2
+test_bucket{le=\"+Inf\"} 3
+test_sum 55.5
+test_count 3
+");
+    }
+
+    #[test]
+    fn test_counters_render() {
+        let metrics = Metrics {
+            http_requests: Counters::new(),
+            rate_limited: AtomicU64::new(0),
+            queue_full: AtomicU64::new(0),
+            batch_size: Histogram::new([0.0; 7]),
+            batch_splits: AtomicU64::new(0),
+            batch_carryovers: AtomicU64::new(0),
+            batch_failures: Counters::new(),
+            inflight_batches: AtomicU64::new(2),
+            tree_depth: Histogram::new([0.0; 6]),
+            upstream_latency: Histogram::new([0.0; 10]),
+            upstream_errors: Counters::new(),
+            timestamp_cache_hits: AtomicU64::new(0),
+            timestamp_cache_misses: AtomicU64::new(0),
+        };
+        metrics.http_requests.inc(("/digest", 200));
+        metrics.http_requests.inc(("/digest", 200));
+        metrics.upstream_errors.inc(("http://a\"b/digest".to_string(), "bad_status"));
+
+        let out = metrics.render(3);
+        assert!(out.contains("foxglove_http_requests_total{route=\"/digest\",status=\"200\"} 2\n"));
+        assert!(out.contains("foxglove_queue_depth 3\n"));
+        assert!(out.contains("foxglove_inflight_batches 2\n"));
+        assert!(out.contains("foxglove_upstream_errors_total{upstream=\"http://a\\\"b/digest\",error=\"bad_status\"} 1\n"));
+    }
+}
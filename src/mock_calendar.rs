@@ -0,0 +1,137 @@
+//! Minimal calendar server for tests, listening on a random loopback port.
+
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::HeaderMap;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use reqwest::Url;
+use tokio::net::TcpListener;
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// Reply with a pending attestation proof for the submitted digest.
+    Proof,
+
+    /// Reply with an empty body and the given status code.
+    Status(StatusCode),
+
+    /// Reply with the given body.
+    Body(Bytes),
+
+    /// Reply with the given status code to the first n requests, and a proof after that.
+    FailFirst(usize, StatusCode),
+
+    /// Reply with a proof after a delay.
+    Delay(Duration),
+}
+
+pub struct MockCalendar {
+    uri: String,
+    hits: Arc<AtomicUsize>,
+    last_headers: Arc<Mutex<HeaderMap>>,
+    last_path: Arc<Mutex<Option<String>>>,
+}
+
+impl MockCalendar {
+    pub async fn start(reply: Reply) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let last_headers = Arc::new(Mutex::new(HeaderMap::new()));
+        let last_path = Arc::new(Mutex::new(None));
+
+        let calendar = Self {
+            uri: uri.clone(),
+            hits: hits.clone(),
+            last_headers: last_headers.clone(),
+            last_path: last_path.clone(),
+        };
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (uri, hits, reply) = (uri.clone(), hits.clone(), reply.clone());
+                let (last_headers, last_path) = (last_headers.clone(), last_path.clone());
+                tokio::task::spawn(async move {
+                    let service = service_fn(move |r: Request<hyper::body::Incoming>| {
+                        let (uri, hits, reply) = (uri.clone(), hits.clone(), reply.clone());
+                        *last_headers.lock().unwrap() = r.headers().clone();
+                        *last_path.lock().unwrap() = Some(r.uri().path().to_string());
+                        async move {
+                            let n = hits.fetch_add(1, Ordering::SeqCst);
+                            let _digest = r.into_body().collect().await.unwrap().to_bytes();
+                            let reply = match reply {
+                                Reply::FailFirst(fails, status) if n < fails => Reply::Status(status),
+                                Reply::FailFirst(_, _) => Reply::Proof,
+                                Reply::Delay(delay) => {
+                                    tokio::time::sleep(delay).await;
+                                    Reply::Proof
+                                },
+                                reply => reply,
+                            };
+                            let response = match reply {
+                                Reply::Proof => Response::new(Full::new(Bytes::from(pending_proof(&uri)))),
+                                Reply::Status(status) => Response::builder()
+                                                                 .status(status)
+                                                                 .body(Full::new(Bytes::new()))
+                                                                 .unwrap(),
+                                Reply::Body(body) => Response::new(Full::new(body)),
+                                Reply::FailFirst(..) | Reply::Delay(_) => unreachable!(),
+                            };
+                            Ok::<_, Infallible>(response)
+                        }
+                    });
+                    let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                });
+            }
+        });
+        calendar
+    }
+
+    /// URL that digests should be submitted to.
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("{}/digest", self.uri)).unwrap()
+    }
+
+    /// Number of requests the calendar has received.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Value of a header in the most recent request.
+    pub fn last_header(&self, name: &str) -> Option<String> {
+        self.last_headers.lock().unwrap().get(name).map(|value| value.to_str().unwrap().to_string())
+    }
+
+    /// Path of the most recent request.
+    pub fn last_path(&self) -> Option<String> {
+        self.last_path.lock().unwrap().clone()
+    }
+
+    /// The proof this calendar replies with.
+    pub fn proof(&self) -> Vec<u8> {
+        pending_proof(&self.uri)
+    }
+}
+
+/// A proof ending in a pending attestation from the calendar at `uri`.
+pub fn pending_proof(uri: &str) -> Vec<u8> {
+    let mut r = vec![];
+    r.push(0xf0); // append
+    r.push(16); // 16 byte nonce
+    r.extend_from_slice(&[0; 16]);
+    r.push(0x08); // sha256
+    r.push(0x00); // attestation
+    r.extend_from_slice(&[0x83, 0xdf, 0xe3, 0x0d, 0x2e, 0xf9, 0x0c, 0x8e]); // pending
+    r.push(uri.len() as u8 + 1); // payload length
+    r.push(uri.len() as u8);
+    r.extend_from_slice(uri.as_bytes());
+    r
+}
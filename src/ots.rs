@@ -0,0 +1,543 @@
+//! Serialization and deserialization of OpenTimestamps proofs, as returned by upstream calendars.
+
+use std::fmt;
+
+use bitcoin_hashes::{Ripemd160, Sha1, Sha256};
+
+const PENDING_TAG: [u8; 8] = [0x83, 0xdf, 0xe3, 0x0d, 0x2e, 0xf9, 0x0c, 0x8e];
+const BITCOIN_TAG: [u8; 8] = [0x05, 0x88, 0x96, 0x0d, 0x73, 0xd7, 0x19, 0x01];
+
+const MAX_OP_ARG_LENGTH: usize = 4096;
+const MAX_PAYLOAD_LENGTH: usize = 8192;
+const MAX_URI_LENGTH: usize = 1000;
+const MAX_RESULT_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Sha1,
+    Ripemd160,
+    Sha256,
+    Keccak256,
+    Append(Vec<u8>),
+    Prepend(Vec<u8>),
+    Reverse,
+    Hexlify,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Attestation {
+    Pending {
+        uri: String,
+    },
+    Bitcoin {
+        height: u64,
+    },
+    Unknown {
+        tag: [u8; 8],
+        payload: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Attestation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Attestation::Pending { uri } => write!(f, "pending at {}", uri),
+            Attestation::Bitcoin { height } => write!(f, "bitcoin block {}", height),
+            Attestation::Unknown { tag, payload } => {
+                write!(f, "unknown tag ")?;
+                for b in tag {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, " with {} byte payload", payload.len())
+            },
+        }
+    }
+}
+
+/// A timestamp proof: a tree of operations, each branch of which ends in one or more attestations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub attestations: Vec<Attestation>,
+    pub ops: Vec<(Op, Timestamp)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum serialized size in bytes.
+    pub max_size: usize,
+
+    /// Maximum number of nested operations.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_size: 10_000,
+            max_depth: 256,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeserializeError {
+    #[error("proof is {0} bytes long, more than the limit of {1}")]
+    TooLarge(usize, usize),
+
+    #[error("proof nested deeper than the limit of {0}")]
+    TooDeep(usize),
+
+    #[error("proof truncated")]
+    Truncated,
+
+    #[error("{0} trailing bytes after proof")]
+    TrailingBytes(usize),
+
+    #[error("unknown op 0x{0:02x}")]
+    UnknownOp(u8),
+
+    #[error("varint too large")]
+    VarintOverflow,
+
+    #[error("length {0} out of range")]
+    BadLength(u64),
+
+    #[error("invalid pending attestation URI")]
+    InvalidUri,
+
+    #[error("{0} trailing bytes in attestation payload")]
+    TrailingPayload(usize),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("{0} op is not supported")]
+    UnsupportedOp(&'static str),
+
+    #[error("op result is {0} bytes long, more than the limit of {MAX_RESULT_LENGTH}")]
+    ResultTooLong(usize),
+
+    #[error("expected pending attestation from {expected}, got {got}")]
+    UnexpectedAttestation {
+        expected: String,
+        got: Attestation,
+    },
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DeserializeError> {
+        if len > self.bytes.len() {
+            return Err(DeserializeError::Truncated);
+        }
+        let (r, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(r)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_varuint(&mut self) -> Result<u64, DeserializeError> {
+        let mut r: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.read_byte()?;
+            let value = u64::from(b & 0x7f);
+            if shift >= 64 || (value << shift) >> shift != value {
+                return Err(DeserializeError::VarintOverflow);
+            }
+            r |= value << shift;
+            if b & 0x80 == 0 {
+                break Ok(r);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_varbytes(&mut self, min: usize, max: usize) -> Result<&'a [u8], DeserializeError> {
+        let len = self.read_varuint()?;
+        if len < min as u64 || len > max as u64 {
+            return Err(DeserializeError::BadLength(len));
+        }
+        self.read_bytes(len as usize)
+    }
+}
+
+fn write_varuint(w: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        w.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    w.push(value as u8);
+}
+
+fn write_varbytes(w: &mut Vec<u8>, bytes: &[u8]) {
+    write_varuint(w, bytes.len() as u64);
+    w.extend_from_slice(bytes);
+}
+
+impl Attestation {
+    fn serialize(&self, w: &mut Vec<u8>) {
+        let mut payload = vec![];
+        let tag = match self {
+            Attestation::Pending { uri } => {
+                write_varbytes(&mut payload, uri.as_bytes());
+                PENDING_TAG
+            },
+            Attestation::Bitcoin { height } => {
+                write_varuint(&mut payload, *height);
+                BITCOIN_TAG
+            },
+            Attestation::Unknown { tag, payload: unknown } => {
+                payload.extend_from_slice(unknown);
+                *tag
+            },
+        };
+        w.extend_from_slice(&tag);
+        write_varbytes(w, &payload);
+    }
+
+    fn deserialize(r: &mut Reader) -> Result<Self, DeserializeError> {
+        let tag: [u8; 8] = r.read_bytes(8)?.try_into().expect("read 8 bytes");
+        let mut payload = Reader { bytes: r.read_varbytes(0, MAX_PAYLOAD_LENGTH)? };
+
+        let attestation = match tag {
+            PENDING_TAG => {
+                let uri = payload.read_varbytes(0, MAX_URI_LENGTH)?;
+                if !uri.iter().all(|c| c.is_ascii_alphanumeric() || b"-._/:".contains(c)) {
+                    return Err(DeserializeError::InvalidUri);
+                }
+                Attestation::Pending {
+                    uri: String::from_utf8(uri.to_vec()).expect("checked to be ascii"),
+                }
+            },
+            BITCOIN_TAG => {
+                Attestation::Bitcoin {
+                    height: payload.read_varuint()?,
+                }
+            },
+            _ => {
+                let payload = payload.read_bytes(payload.bytes.len())?;
+                Attestation::Unknown { tag, payload: payload.to_vec() }
+            },
+        };
+
+        if !payload.bytes.is_empty() {
+            return Err(DeserializeError::TrailingPayload(payload.bytes.len()));
+        }
+        Ok(attestation)
+    }
+}
+
+impl Op {
+    fn serialize(&self, w: &mut Vec<u8>) {
+        match self {
+            Op::Sha1 => w.push(0x02),
+            Op::Ripemd160 => w.push(0x03),
+            Op::Sha256 => w.push(0x08),
+            Op::Keccak256 => w.push(0x67),
+            Op::Append(arg) => {
+                w.push(0xf0);
+                write_varbytes(w, arg);
+            },
+            Op::Prepend(arg) => {
+                w.push(0xf1);
+                write_varbytes(w, arg);
+            },
+            Op::Reverse => w.push(0xf2),
+            Op::Hexlify => w.push(0xf3),
+        }
+    }
+
+    fn deserialize(r: &mut Reader, tag: u8) -> Result<Self, DeserializeError> {
+        Ok(match tag {
+            0x02 => Op::Sha1,
+            0x03 => Op::Ripemd160,
+            0x08 => Op::Sha256,
+            0x67 => Op::Keccak256,
+            0xf0 => Op::Append(r.read_varbytes(1, MAX_OP_ARG_LENGTH)?.to_vec()),
+            0xf1 => Op::Prepend(r.read_varbytes(1, MAX_OP_ARG_LENGTH)?.to_vec()),
+            0xf2 => Op::Reverse,
+            0xf3 => Op::Hexlify,
+            tag => return Err(DeserializeError::UnknownOp(tag)),
+        })
+    }
+
+    /// Applies the op to a message, returning the result.
+    pub fn apply(&self, msg: &[u8]) -> Result<Vec<u8>, VerifyError> {
+        let r = match self {
+            Op::Sha1 => Sha1::hash(msg).to_byte_array().to_vec(),
+            Op::Ripemd160 => Ripemd160::hash(msg).to_byte_array().to_vec(),
+            Op::Sha256 => Sha256::hash(msg).to_byte_array().to_vec(),
+            Op::Keccak256 => return Err(VerifyError::UnsupportedOp("keccak256")),
+            Op::Append(arg) => [msg, arg].concat(),
+            Op::Prepend(arg) => [arg, msg].concat(),
+            Op::Reverse => msg.iter().rev().copied().collect(),
+            Op::Hexlify => msg.iter().flat_map(|b| format!("{:02x}", b).into_bytes()).collect(),
+        };
+        if r.len() > MAX_RESULT_LENGTH {
+            return Err(VerifyError::ResultTooLong(r.len()));
+        }
+        Ok(r)
+    }
+}
+
+impl Timestamp {
+    /// Serializes the proof, attestations before ops at each fork.
+    ///
+    /// Deserializing the result gives back an equal proof, though not necessarily the same bytes
+    /// it was deserialized from, as those may order forks differently or pad varints.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut w = vec![];
+        self.serialize_into(&mut w);
+        w
+    }
+
+    fn serialize_into(&self, w: &mut Vec<u8>) {
+        let branches = self.attestations.len() + self.ops.len();
+        for (i, attestation) in self.attestations.iter().enumerate() {
+            if i + 1 < branches {
+                w.push(0xff);
+            }
+            w.push(0x00);
+            attestation.serialize(w);
+        }
+        for (i, (op, stamp)) in self.ops.iter().enumerate() {
+            if self.attestations.len() + i + 1 < branches {
+                w.push(0xff);
+            }
+            op.serialize(w);
+            stamp.serialize_into(w);
+        }
+    }
+
+    /// Deserializes a complete proof.
+    ///
+    /// The serialization grammar requires every branch to end in an attestation, so a successfully
+    /// deserialized proof always has at least one.
+    pub fn deserialize(bytes: &[u8], limits: Limits) -> Result<Self, DeserializeError> {
+        if bytes.len() > limits.max_size {
+            return Err(DeserializeError::TooLarge(bytes.len(), limits.max_size));
+        }
+
+        let mut r = Reader { bytes };
+        let stamp = Self::deserialize_at(&mut r, 0, limits)?;
+        if !r.bytes.is_empty() {
+            return Err(DeserializeError::TrailingBytes(r.bytes.len()));
+        }
+        Ok(stamp)
+    }
+
+    fn deserialize_at(r: &mut Reader, depth: usize, limits: Limits) -> Result<Self, DeserializeError> {
+        let mut stamp = Timestamp::default();
+
+        let mut tag = r.read_byte()?;
+        while tag == 0xff {
+            let current_tag = r.read_byte()?;
+            stamp.deserialize_tag(r, current_tag, depth, limits)?;
+            tag = r.read_byte()?;
+        }
+        stamp.deserialize_tag(r, tag, depth, limits)?;
+
+        Ok(stamp)
+    }
+
+    fn deserialize_tag(&mut self, r: &mut Reader, tag: u8, depth: usize, limits: Limits) -> Result<(), DeserializeError> {
+        if tag == 0x00 {
+            self.attestations.push(Attestation::deserialize(r)?);
+        } else {
+            let op = Op::deserialize(r, tag)?;
+            if depth >= limits.max_depth {
+                return Err(DeserializeError::TooDeep(limits.max_depth));
+            }
+            self.ops.push((op, Self::deserialize_at(r, depth + 1, limits)?));
+        }
+        Ok(())
+    }
+
+    /// Checks that the proof, applied to `msg`, only ends in pending attestations from the calendar
+    /// at `calendar_uri`.
+    pub fn verify_pending(&self, msg: &[u8], calendar_uri: &str) -> Result<(), VerifyError> {
+        for attestation in self.attestations.iter() {
+            match attestation {
+                Attestation::Pending { uri } if uri == calendar_uri => {},
+                _ => {
+                    return Err(VerifyError::UnexpectedAttestation {
+                        expected: calendar_uri.to_string(),
+                        got: attestation.clone(),
+                    });
+                },
+            }
+        }
+        for (op, stamp) in self.ops.iter() {
+            stamp.verify_pending(&op.apply(msg)?, calendar_uri)?;
+        }
+        Ok(())
+    }
+
+    /// All attestations in the proof, depth first.
+    pub fn all_attestations(&self) -> Vec<&Attestation> {
+        let mut r: Vec<&Attestation> = self.attestations.iter().collect();
+        for (_, stamp) in self.ops.iter() {
+            r.extend(stamp.all_attestations());
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(uri: &str) -> Vec<u8> {
+        let mut r = vec![0x00];
+        r.extend_from_slice(&PENDING_TAG);
+        r.push(uri.len() as u8 + 1);
+        r.push(uri.len() as u8);
+        r.extend_from_slice(uri.as_bytes());
+        r
+    }
+
+    #[test]
+    fn test_deserialize_pending() {
+        let mut proof = vec![0xf0, 3, 1, 2, 3, 0x08];
+        proof.extend(pending("https://alice.btc.calendar.opentimestamps.org"));
+
+        let stamp = Timestamp::deserialize(&proof, Limits::default()).unwrap();
+        assert_eq!(stamp,
+                   Timestamp {
+                       attestations: vec![],
+                       ops: vec![(Op::Append(vec![1, 2, 3]),
+                                  Timestamp {
+                                      attestations: vec![],
+                                      ops: vec![(Op::Sha256,
+                                                 Timestamp {
+                                                     attestations: vec![Attestation::Pending {
+                                                         uri: "https://alice.btc.calendar.opentimestamps.org".into()
+                                                     }],
+                                                     ops: vec![],
+                                                 })],
+                                  })],
+                   });
+    }
+
+    #[test]
+    fn test_deserialize_fork() {
+        // fork: reverse -> bitcoin; prepend -> unknown; pending
+        let mut proof = vec![0xff, 0xf2, 0x00];
+        proof.extend_from_slice(&BITCOIN_TAG);
+        proof.extend_from_slice(&[3, 0xe5, 0x8e, 0x26]); // 624485
+        proof.extend_from_slice(&[0xff, 0xf1, 1, 0xaa, 0x00, 1, 2, 3, 4, 5, 6, 7, 8, 2, 0xbe, 0xef]);
+        proof.extend(pending("http://127.0.0.1:1234"));
+
+        let stamp = Timestamp::deserialize(&proof, Limits::default()).unwrap();
+        assert_eq!(stamp.ops.len(), 2);
+        assert_eq!(stamp.all_attestations(),
+                   vec![&Attestation::Pending { uri: "http://127.0.0.1:1234".into() },
+                        &Attestation::Bitcoin { height: 624485 },
+                        &Attestation::Unknown { tag: [1, 2, 3, 4, 5, 6, 7, 8], payload: vec![0xbe, 0xef] }]);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let mut proof = vec![0xff, 0x00];
+        proof.extend_from_slice(&BITCOIN_TAG);
+        proof.extend_from_slice(&[3, 0xe5, 0x8e, 0x26]);
+        proof.extend_from_slice(&[0xff, 0xf1, 1, 0xaa, 0x00, 1, 2, 3, 4, 5, 6, 7, 8, 2, 0xbe, 0xef]);
+        proof.extend_from_slice(&[0xf0, 3, 1, 2, 3, 0x08]);
+        proof.extend(pending("http://127.0.0.1:1234"));
+        let stamp = Timestamp::deserialize(&proof, Limits::default()).unwrap();
+        assert_eq!(stamp.serialize(), proof);
+
+        // Forks are reordered, attestations first, and padded varints are shortened
+        let mut proof = vec![0xff, 0xf0, 0x81, 0x00, 0xaa];
+        proof.extend(pending("http://example.com"));
+        proof.extend(pending("http://example.org"));
+        let stamp = Timestamp::deserialize(&proof, Limits::default()).unwrap();
+        let serialized = stamp.serialize();
+        assert_eq!(serialized[.. 2], [0xff, 0x00]);
+        assert_eq!(serialized.len(), proof.len() - 1);
+        assert_eq!(Timestamp::deserialize(&serialized, Limits::default()).unwrap(), stamp);
+    }
+
+    #[test]
+    fn test_deserialize_invalid() {
+        let limits = Limits::default();
+        let valid = pending("http://example.com");
+
+        assert!(matches!(Timestamp::deserialize(&[], limits), Err(DeserializeError::Truncated)));
+        assert!(matches!(Timestamp::deserialize(&valid[.. valid.len() - 1], limits), Err(DeserializeError::Truncated)));
+        assert!(matches!(Timestamp::deserialize(&[0x08], limits), Err(DeserializeError::Truncated)));
+        assert!(matches!(Timestamp::deserialize(&[0xff], limits), Err(DeserializeError::Truncated)));
+        assert!(matches!(Timestamp::deserialize(&[0x42], limits), Err(DeserializeError::UnknownOp(0x42))));
+        assert!(matches!(Timestamp::deserialize(&[0xf0, 0x00], limits), Err(DeserializeError::BadLength(0))));
+        assert!(matches!(Timestamp::deserialize(&[0xf0, 0x81, 0x40], limits), Err(DeserializeError::BadLength(8193))));
+        assert!(matches!(Timestamp::deserialize(&[0xf0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f], limits),
+                         Err(DeserializeError::VarintOverflow)));
+        assert!(matches!(Timestamp::deserialize(&pending("http://example.com/?a=b"), limits), Err(DeserializeError::InvalidUri)));
+
+        let mut trailing = valid.clone();
+        trailing.push(0x08);
+        assert!(matches!(Timestamp::deserialize(&trailing, limits), Err(DeserializeError::TrailingBytes(1))));
+
+        let mut trailing_payload = vec![0x00];
+        trailing_payload.extend_from_slice(&BITCOIN_TAG);
+        trailing_payload.extend_from_slice(&[2, 1, 0]);
+        assert!(matches!(Timestamp::deserialize(&trailing_payload, limits), Err(DeserializeError::TrailingPayload(1))));
+    }
+
+    #[test]
+    fn test_op_apply() {
+        assert_eq!(Op::Append(vec![3, 4]).apply(&[1, 2]).unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(Op::Prepend(vec![3, 4]).apply(&[1, 2]).unwrap(), vec![3, 4, 1, 2]);
+        assert_eq!(Op::Reverse.apply(&[1, 2]).unwrap(), vec![2, 1]);
+        assert_eq!(Op::Hexlify.apply(&[0xab, 0x01]).unwrap(), b"ab01".to_vec());
+        assert_eq!(Op::Sha256.apply(b"").unwrap(),
+                   [0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+                    0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55]);
+        assert_eq!(Op::Sha1.apply(b"").unwrap().len(), 20);
+        assert_eq!(Op::Ripemd160.apply(b"").unwrap().len(), 20);
+        assert!(matches!(Op::Keccak256.apply(b""), Err(VerifyError::UnsupportedOp(_))));
+        assert!(matches!(Op::Append(vec![0; 4096]).apply(&[0]), Err(VerifyError::ResultTooLong(4097))));
+    }
+
+    #[test]
+    fn test_verify_pending() {
+        let mut proof = vec![0xff, 0xf0, 1, 0xaa, 0x08];
+        proof.extend(pending("http://example.com"));
+        proof.extend_from_slice(&[0xf1, 1, 0xbb, 0x08]);
+        proof.extend(pending("http://example.com"));
+        let stamp = Timestamp::deserialize(&proof, Limits::default()).unwrap();
+
+        stamp.verify_pending(&[0; 32], "http://example.com").unwrap();
+        assert!(matches!(stamp.verify_pending(&[0; 32], "http://example.org"),
+                         Err(VerifyError::UnexpectedAttestation { .. })));
+
+        let mut proof = vec![0xff, 0x08];
+        proof.extend(pending("http://example.com"));
+        proof.extend_from_slice(&[0x00]);
+        proof.extend_from_slice(&BITCOIN_TAG);
+        proof.extend_from_slice(&[1, 0]);
+        let stamp = Timestamp::deserialize(&proof, Limits::default()).unwrap();
+        assert!(matches!(stamp.verify_pending(&[0; 32], "http://example.com"),
+                         Err(VerifyError::UnexpectedAttestation { got: Attestation::Bitcoin { height: 0 }, .. })));
+    }
+
+    #[test]
+    fn test_deserialize_limits() {
+        let valid = pending("http://example.com");
+        let mut deep = vec![0x08; 10];
+        deep.extend_from_slice(&valid);
+
+        assert!(Timestamp::deserialize(&deep, Limits { max_size: deep.len(), max_depth: 10 }).is_ok());
+        assert!(matches!(Timestamp::deserialize(&deep, Limits { max_size: deep.len() - 1, max_depth: 10 }),
+                         Err(DeserializeError::TooLarge(_, _))));
+        assert!(matches!(Timestamp::deserialize(&deep, Limits { max_size: deep.len(), max_depth: 9 }),
+                         Err(DeserializeError::TooDeep(9))));
+    }
+}
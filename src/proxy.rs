@@ -0,0 +1,324 @@
+//! Proxying of timestamp upgrade requests to the upstream calendars.
+
+use std::collections::HashMap;
+use std::num::NonZero;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bitcoin_hashes::Sha256;
+use hyper::body::Bytes;
+use lru::LruCache;
+use tokio::sync::OnceCell;
+
+use crate::aggregator::StampRequestError;
+use crate::metrics::{self, METRICS};
+use crate::ots::Attestation;
+use crate::upstream::Upstreams;
+
+/// Maximum length of a commitment, in bytes.
+pub const MAX_COMMITMENT_LEN: usize = 64;
+
+/// Parses a hex-encoded commitment, returning it lowercased.
+pub fn parse_commitment(hex: &str) -> Option<String> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) || hex.len() > MAX_COMMITMENT_LEN * 2
+       || !hex.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    Some(hex.to_ascii_lowercase())
+}
+
+/// How long upstream replies are cached for.
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    /// Maximum number of cached replies; zero disables the cache.
+    pub capacity: usize,
+
+    /// For timestamps that are still pending, and commitments no upstream has.
+    pub pending_ttl: Duration,
+
+    /// For timestamps with a Bitcoin attestation, which won't change any more.
+    pub complete_ttl: Duration,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            pending_ttl: Duration::from_secs(10),
+            complete_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Reply to a timestamp upgrade request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upgrade {
+    /// The timestamp, or `None` if no upstream has the commitment.
+    pub proof: Option<Bytes>,
+
+    /// Entity tag of the timestamp, quoted.
+    pub etag: Option<String>,
+
+    /// How much longer the reply may be cached.
+    pub max_age: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    expires: Instant,
+    proof: Option<Bytes>,
+    etag: Option<String>,
+}
+
+impl Entry {
+    fn upgrade(&self) -> Upgrade {
+        Upgrade {
+            proof: self.proof.clone(),
+            etag: self.etag.clone(),
+            max_age: self.expires.saturating_duration_since(Instant::now()),
+        }
+    }
+}
+
+/// Entity tag for a timestamp: a truncated hash of its serialization.
+fn etag(proof: &[u8]) -> String {
+    let hash = Sha256::hash(proof).to_byte_array();
+    let hex: String = hash[.. 16].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// Reply to an upstream request, shared by every client waiting on it.
+type Fetch = Arc<OnceCell<Result<Upgrade, Arc<StampRequestError>>>>;
+
+/// Proxies `/timestamp/<commitment>` requests upstream, caching replies in an LRU cache.
+///
+/// Concurrent misses for the same commitment, as when every client in a tree upgrades at once,
+/// share a single upstream request.
+#[derive(Debug)]
+pub struct TimestampProxy {
+    policy: CachePolicy,
+    cache: Option<Mutex<LruCache<String, Entry>>>,
+    inflight: Mutex<HashMap<String, Fetch>>,
+}
+
+impl TimestampProxy {
+    pub fn new(policy: CachePolicy) -> Self {
+        Self {
+            cache: NonZero::new(policy.capacity).map(|capacity| Mutex::new(LruCache::new(capacity))),
+            inflight: Mutex::new(HashMap::new()),
+            policy,
+        }
+    }
+
+    fn cached(&self, commitment: &str) -> Option<Upgrade> {
+        let mut cache = self.cache.as_ref()?.lock().unwrap();
+        match cache.get(commitment) {
+            Some(entry) if Instant::now() < entry.expires => Some(entry.upgrade()),
+            Some(_) => {
+                cache.pop(commitment);
+                None
+            },
+            None => None,
+        }
+    }
+
+    /// Gets the timestamp for a commitment, from the cache if possible.
+    pub async fn get(&self, upstreams: &Upstreams, commitment: &str) -> Result<Upgrade, Arc<StampRequestError>> {
+        if let Some(upgrade) = self.cached(commitment) {
+            metrics::inc(&METRICS.timestamp_cache_hits);
+            return Ok(upgrade);
+        }
+        metrics::inc(&METRICS.timestamp_cache_misses);
+
+        let fetch = Arc::clone(self.inflight.lock().unwrap().entry(commitment.to_string()).or_default());
+        let result = fetch.get_or_init(|| self.fetch(upstreams, commitment)).await.clone();
+
+        // Whoever gets here first retires the request; later misses go to the cache, or upstream
+        // again if it failed.
+        let mut inflight = self.inflight.lock().unwrap();
+        if inflight.get(commitment).is_some_and(|current| Arc::ptr_eq(current, &fetch)) {
+            inflight.remove(commitment);
+        }
+        result
+    }
+
+    async fn fetch(&self, upstreams: &Upstreams, commitment: &str) -> Result<Upgrade, Arc<StampRequestError>> {
+        // Errors aren't cached, so a flaky upstream is retried on the next request.
+        let entry = match upstreams.get_timestamp(commitment).await? {
+            Some((proof, stamp)) => {
+                let complete = stamp.all_attestations().iter()
+                                    .any(|attestation| matches!(attestation, Attestation::Bitcoin { .. }));
+                Entry {
+                    expires: Instant::now() + if complete { self.policy.complete_ttl } else { self.policy.pending_ttl },
+                    etag: Some(etag(&proof)),
+                    proof: Some(proof),
+                }
+            },
+            None => Entry {
+                expires: Instant::now() + self.policy.pending_ttl,
+                proof: None,
+                etag: None,
+            },
+        };
+        let upgrade = entry.upgrade();
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().put(commitment.to_string(), entry);
+        }
+        Ok(upgrade)
+    }
+}
+
+/// Whether an `If-None-Match` header value matches the entity tag.
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',')
+                 .map(|tag| tag.trim().trim_start_matches("W/"))
+                 .any(|tag| tag == "*" || tag == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::StatusCode;
+
+    use crate::mock_calendar::{MockCalendar, Reply};
+    use crate::ots;
+    use crate::upstream::UpstreamsConfig;
+
+    fn policy(capacity: usize) -> CachePolicy {
+        CachePolicy {
+            capacity,
+            pending_ttl: Duration::from_millis(100),
+            complete_ttl: Duration::from_secs(60),
+        }
+    }
+
+    /// A timestamp attested in Bitcoin block 1.
+    fn bitcoin_proof() -> Bytes {
+        let mut r = vec![0x08, 0x00];
+        r.extend_from_slice(&[0x05, 0x88, 0x96, 0x0d, 0x73, 0xd7, 0x19, 0x01]);
+        r.extend_from_slice(&[1, 1]);
+        Bytes::from(r)
+    }
+
+    #[test]
+    fn test_parse_commitment() {
+        assert_eq!(parse_commitment("00AbCd"), Some("00abcd".to_string()));
+        assert_eq!(parse_commitment(""), None);
+        assert_eq!(parse_commitment("abc"), None);
+        assert_eq!(parse_commitment("zz"), None);
+        assert_eq!(parse_commitment(&"00".repeat(MAX_COMMITMENT_LEN + 1)), None);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let tag = etag(b"proof");
+        assert!(etag_matches(&tag, &tag));
+        assert!(etag_matches(&format!("\"x\", W/{}", tag), &tag));
+        assert!(etag_matches("*", &tag));
+        assert!(!etag_matches("\"x\"", &tag));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_cache() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        let proxy = TimestampProxy::new(policy(10));
+
+        let upgrade = proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(upgrade.proof.unwrap(), calendar.proof());
+        assert_eq!(upgrade.etag.unwrap(), etag(&calendar.proof()));
+        assert!(upgrade.max_age <= Duration::from_millis(100));
+
+        let upgrade = proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(upgrade.proof.unwrap(), calendar.proof());
+        assert_eq!(calendar.hits(), 1);
+        assert_eq!(calendar.last_path().unwrap(), "/timestamp/00");
+
+        // Pending timestamps expire quickly
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(calendar.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_cache_complete() {
+        let calendar = MockCalendar::start(Reply::Body(bitcoin_proof())).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        let proxy = TimestampProxy::new(policy(10));
+
+        let upgrade = proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(upgrade.proof.unwrap(), bitcoin_proof());
+        assert!(upgrade.max_age > Duration::from_secs(50));
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(calendar.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_cache_lru() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        let proxy = TimestampProxy::new(policy(2));
+
+        proxy.get(&upstreams, "00").await.unwrap();
+        proxy.get(&upstreams, "01").await.unwrap();
+        proxy.get(&upstreams, "00").await.unwrap();
+        proxy.get(&upstreams, "02").await.unwrap(); // evicts 01
+        assert_eq!(calendar.hits(), 3);
+
+        proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(calendar.hits(), 3);
+        proxy.get(&upstreams, "01").await.unwrap();
+        assert_eq!(calendar.hits(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_not_found() {
+        let missing = MockCalendar::start(Reply::Status(StatusCode::NOT_FOUND)).await;
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let proxy = TimestampProxy::new(policy(10));
+
+        // Falls through to the calendar that has the commitment
+        let upstreams = Upstreams::new([missing.url(), calendar.url()], UpstreamsConfig::default()).unwrap();
+        assert_eq!(proxy.get(&upstreams, "00").await.unwrap().proof.unwrap(), calendar.proof());
+
+        let upstreams = Upstreams::new([missing.url()], UpstreamsConfig::default()).unwrap();
+        let upgrade = proxy.get(&upstreams, "01").await.unwrap();
+        assert_eq!((upgrade.proof, upgrade.etag), (None, None));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_coalesces_misses() {
+        let calendar = MockCalendar::start(Reply::Delay(Duration::from_millis(100))).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        let proxy = TimestampProxy::new(policy(0));
+
+        // Even with the cache disabled, concurrent requests share one upstream request
+        let upgrades = futures::future::join_all((0 .. 10).map(|_| proxy.get(&upstreams, "00"))).await;
+        for upgrade in upgrades {
+            assert_eq!(upgrade.unwrap().proof.unwrap(), calendar.proof());
+        }
+        assert_eq!(calendar.hits(), 1);
+        assert!(proxy.inflight.lock().unwrap().is_empty());
+
+        proxy.get(&upstreams, "00").await.unwrap();
+        assert_eq!(calendar.hits(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_errors() {
+        let calendar = MockCalendar::start(Reply::Body(Bytes::from(vec![0xf0; 20_000]))).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        let proxy = TimestampProxy::new(policy(10));
+
+        let err = proxy.get(&upstreams, "00").await.unwrap_err();
+        assert!(matches!(*err, StampRequestError::InvalidProof(ots::DeserializeError::TooLarge(..))));
+
+        // Not cached
+        proxy.get(&upstreams, "00").await.unwrap_err();
+        assert_eq!(calendar.hits(), 2);
+    }
+}
@@ -0,0 +1,274 @@
+//! Token bucket rate limiting of digest submissions, per client IP address or IPv6 /64.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZero;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::HeaderMap;
+use lru::LruCache;
+
+/// Number of clients tracked; beyond that the least recently seen are forgotten.
+const MAX_TRACKED_CLIENTS: NonZero<usize> = NonZero::new(100_000).unwrap();
+
+/// IPv6 clients share a bucket per /64, as that's what a single site is usually given.
+const IPV6_CLIENT_PREFIX_LEN: u32 = 64;
+
+/// A range of IP addresses in CIDR notation; a bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseIpRangeError {
+    #[error("invalid address: {0}")]
+    Addr(#[from] std::net::AddrParseError),
+
+    #[error("invalid prefix length")]
+    PrefixLen,
+}
+
+impl FromStr for IpRange {
+    type Err = ParseIpRangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr.parse()?, Some(prefix_len)),
+            None => (s.parse()?, None),
+        };
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().ok().filter(|len| *len <= max_len)
+                                          .ok_or(ParseIpRangeError::PrefixLen)?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn prefix_matches(a: u128, b: u128, bits: u32, prefix_len: u8) -> bool {
+            let shift = bits - u32::from(prefix_len);
+            shift >= bits || (a >> shift) == (b >> shift)
+        }
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(range).into(), u32::from(ip).into(), 32, self.prefix_len)
+            },
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(range), u128::from(ip), 128, self.prefix_len)
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Rate limit settings.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Sustained requests per second allowed per client.
+    pub rate: f64,
+
+    /// Requests a client may make at once after being idle.
+    pub burst: f64,
+
+    /// Proxies whose `X-Forwarded-For` header is trusted to name the client.
+    pub trusted_proxies: Vec<IpRange>,
+
+    /// Clients that are never limited.
+    pub allowlist: Vec<IpRange>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<LruCache<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        assert!(config.rate > 0.0 && config.burst >= 1.0);
+        Self {
+            config,
+            buckets: Mutex::new(LruCache::new(MAX_TRACKED_CLIENTS)),
+        }
+    }
+
+    /// Works out the client's address from the peer address and any `X-Forwarded-For` headers.
+    ///
+    /// The header is only believed if the peer is a trusted proxy, and then only up to the first
+    /// address that isn't itself a trusted proxy, as anything before it could be spoofed.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let trusted = |ip: IpAddr| self.config.trusted_proxies.iter().any(|range| range.contains(ip));
+        if !trusted(peer) {
+            return peer;
+        }
+
+        // Each proxy appends the address it got the request from, so only the rightmost entries
+        // can be relied on; anything the client sent is on the left.
+        let forwarded: Vec<&str> = headers.get_all("x-forwarded-for").iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        let mut client = peer;
+        for entry in forwarded.into_iter().rev() {
+            match entry.trim().parse() {
+                Ok(ip) if trusted(client) => client = ip,
+                _ => break,
+            }
+        }
+        client
+    }
+
+    /// Takes a token from the client's bucket, or returns how long until one is available.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        if self.config.allowlist.iter().any(|range| range.contains(client)) {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(bucket_key(client), || Bucket { tokens: self.config.burst, updated: now });
+        *bucket = self.refill(*bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.config.rate))
+        }
+    }
+
+    fn refill(&self, bucket: Bucket, now: Instant) -> Bucket {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        Bucket {
+            tokens: (bucket.tokens + elapsed * self.config.rate).min(self.config.burst),
+            updated: now,
+        }
+    }
+}
+
+/// Address whose bucket a client uses.
+fn bucket_key(client: IpAddr) -> IpAddr {
+    match client.to_canonical() {
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX << (128 - IPV6_CLIENT_PREFIX_LEN);
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+        },
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(trusted_proxies: &[&str], allowlist: &[&str]) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            rate: 10.0,
+            burst: 2.0,
+            trusted_proxies: trusted_proxies.iter().map(|range| range.parse().unwrap()).collect(),
+            allowlist: allowlist.iter().map(|range| range.parse().unwrap()).collect(),
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(!range.contains(ip("10.2.0.0")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("::1")));
+
+        assert!("0.0.0.0/0".parse::<IpRange>().unwrap().contains(ip("1.2.3.4")));
+        assert!("::1".parse::<IpRange>().unwrap().contains(ip("::1")));
+        assert!("fd00::/8".parse::<IpRange>().unwrap().contains(ip("fdab::1")));
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let limiter = limiter(&[], &["192.168.0.0/16"]);
+
+        assert_eq!(limiter.check(ip("1.2.3.4")), Ok(()));
+        assert_eq!(limiter.check(ip("1.2.3.4")), Ok(()));
+        let retry_after = limiter.check(ip("1.2.3.4")).unwrap_err();
+        assert!(retry_after <= Duration::from_millis(100));
+
+        // Other clients have their own buckets, and allowlisted ones aren't limited
+        assert_eq!(limiter.check(ip("1.2.3.5")), Ok(()));
+        for _ in 0 .. 10 {
+            assert_eq!(limiter.check(ip("192.168.1.1")), Ok(()));
+        }
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(limiter.check(ip("1.2.3.4")), Ok(()));
+    }
+
+    #[test]
+    fn test_rate_limit_ipv6_prefix() {
+        let limiter = limiter(&[], &[]);
+
+        // Addresses in the same /64 share a bucket
+        assert_eq!(limiter.check(ip("2001:db8::1")), Ok(()));
+        assert_eq!(limiter.check(ip("2001:db8::ffff:1")), Ok(()));
+        assert!(limiter.check(ip("2001:db8::2")).is_err());
+        assert_eq!(limiter.check(ip("2001:db8:0:1::1")), Ok(()));
+
+        assert_eq!(bucket_key(ip("::ffff:1.2.3.4")), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn test_rate_limit_tracked_clients() {
+        let limiter = limiter(&[], &[]);
+        for i in 0 .. MAX_TRACKED_CLIENTS.get() as u32 + 10 {
+            limiter.check(IpAddr::V4(i.into())).unwrap();
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_TRACKED_CLIENTS.get());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let limiter = limiter(&["10.0.0.0/8"], &[]);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2".parse().unwrap());
+
+        // Untrusted peers can't claim to be someone else
+        assert_eq!(limiter.client_ip(ip("5.5.5.5"), &headers), ip("5.5.5.5"));
+
+        // The rightmost address not belonging to a trusted proxy is the client
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("1.2.3.4"));
+
+        // Whatever the client put on the left doesn't matter, even if it isn't an address
+        headers.insert("x-forwarded-for", "garbage, 1.2.3.4".parse().unwrap());
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("1.2.3.4"));
+        headers.insert("x-forwarded-for", "1.2.3.4, garbage, 10.0.0.2".parse().unwrap());
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("10.0.0.2"));
+
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &HeaderMap::new()), ip("10.0.0.1"));
+    }
+}
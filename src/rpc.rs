@@ -1,4 +1,8 @@
+use std::fmt;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use http_body_util::{Full, Limited, BodyExt, LengthLimitError};
 use hyper::http;
@@ -6,11 +10,117 @@ use hyper::body::Bytes;
 use hyper::service::Service;
 use hyper::{Request, Response};
 use http::status::StatusCode;
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
 
-use crate::aggregator::StampRequest;
+use crate::aggregator::{BatchConfig, StampRequest, StampRequestError};
+use crate::metrics::{self, METRICS};
+use crate::proxy::{self, TimestampProxy};
+use crate::ratelimit::RateLimiter;
+use crate::upstream::{RoundStatus, UpstreamStatus, Upstreams};
 
-fn do_get_root(our_name: String, upstream_name: String) -> Response<Full<Bytes>> {
+/// Identifies a request in both the response and our logs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RequestId(u64);
+
+impl RequestId {
+    fn new() -> Self {
+        Self(rand::random())
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Header carrying the request ID.
+const REQUEST_ID: &str = "x-request-id";
+
+/// Error response with a stable code and a generic message; details only go in our logs.
+fn error_response(status: StatusCode, code: &str, message: &str, request_id: RequestId) -> Response<Full<Bytes>> {
+    Response::builder()
+             .status(status)
+             .header(http::header::CONTENT_TYPE, "text/plain")
+             .body(Full::new(Bytes::from(format!("{message}\ncode: {code}\nrequest id: {request_id}\n"))))
+             .unwrap()
+}
+
+/// Adds a `Retry-After` header, in whole seconds rounded up.
+fn with_retry_after(mut response: Response<Full<Bytes>>, retry_after: Duration) -> Response<Full<Bytes>> {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0).to_string();
+    response.headers_mut().insert(http::header::RETRY_AFTER, secs.parse().expect("a number is a valid header value"));
+    response
+}
+
+/// Status code and message to give clients for an upstream error.
+fn client_error(err: &StampRequestError) -> (StatusCode, &'static str) {
+    match err {
+        StampRequestError::Upstream(_) | StampRequestError::BadStatus(_) => {
+            (StatusCode::BAD_GATEWAY, "upstream calendar failed")
+        },
+        StampRequestError::InvalidProof(_) | StampRequestError::RejectedProof(_) => {
+            (StatusCode::BAD_GATEWAY, "upstream calendar returned an invalid timestamp")
+        },
+        StampRequestError::DeadlineExceeded => (StatusCode::GATEWAY_TIMEOUT, "upstream calendar timed out"),
+        StampRequestError::CircuitOpen => (StatusCode::SERVICE_UNAVAILABLE, "upstream calendar unavailable"),
+        StampRequestError::NoQuorum { .. } => (StatusCode::BAD_GATEWAY, "not enough upstream calendars succeeded"),
+    }
+}
+
+fn upstream_error_response(err: &StampRequestError, request_id: RequestId) -> Response<Full<Bytes>> {
+    let (status, message) = client_error(err);
+    error_response(status, err.name(), message, request_id)
+}
+
+/// Static facts about this aggregator, shared by all connections.
+#[derive(Debug)]
+pub struct ServerInfo {
+    pub our_name: String,
+    pub upstream_calendar_name: String,
+    pub batch_config: BatchConfig,
+    pub started: Instant,
+    pub readiness: Readiness,
+
+    /// How long a digest submission waits for room in a full queue before failing with 503;
+    /// `None` waits indefinitely.
+    pub queue_full_wait: Option<Duration>,
+}
+
+/// When `/readyz` reports that we should not be sent requests.
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    /// How long batches may keep failing upstream.
+    pub max_failing: Duration,
+
+    /// Fraction of the request queue that may be in use.
+    pub max_queue_fill: f64,
+}
+
+impl Readiness {
+    /// Checks whether we are ready to take requests, returning why not otherwise.
+    fn check(&self, req_sender: &tokio::sync::mpsc::Sender<StampRequest>, upstreams: &Upstreams) -> Result<(), String> {
+        if req_sender.is_closed() {
+            return Err("aggregator task is not running".to_string());
+        }
+        if let Err(retry_after) = upstreams.available() {
+            return Err(format!("upstream circuit breakers are open for another {:?}", retry_after));
+        }
+        if let Some(failing) = upstreams.failing_for().filter(|failing| *failing > self.max_failing) {
+            return Err(format!("upstream has been failing for {:?}", failing));
+        }
+        let queue_depth = req_sender.max_capacity() - req_sender.capacity();
+        if queue_depth as f64 > req_sender.max_capacity() as f64 * self.max_queue_fill {
+            return Err(format!("request queue is saturated ({} of {})", queue_depth, req_sender.max_capacity()));
+        }
+        Ok(())
+    }
+}
+
+fn do_get_root(info: &ServerInfo) -> Response<Full<Bytes>> {
     let our_version = env!("CARGO_PKG_VERSION");
+    let our_name = &info.our_name;
+    let upstream_name = &info.upstream_calendar_name;
     let body = format!(
 "<html>
 <head>
@@ -40,21 +150,203 @@ fn do_get_favicon() -> Response<Full<Bytes>> {
              .unwrap()
 }
 
+fn do_get_metrics(req_sender: &tokio::sync::mpsc::Sender<StampRequest>) -> Response<Full<Bytes>> {
+    let queue_depth = req_sender.max_capacity() - req_sender.capacity();
+    Response::builder()
+             .status(StatusCode::OK)
+             .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+             .body(Full::new(Bytes::from(METRICS.render(queue_depth))))
+             .unwrap()
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Status<'a> {
+    version: &'static str,
+    our_name: &'a str,
+    upstream_calendar_name: &'a str,
+
+    /// Batching periods, in seconds; the current one follows the upstream latency.
+    period: f64,
+    min_period: f64,
+    max_period: f64,
+    current_period: f64,
+
+    queue_depth: usize,
+    queue_capacity: usize,
+
+    #[serde(flatten)]
+    rounds: RoundStatus,
+
+    /// Seconds since the aggregator started.
+    uptime: f64,
+
+    upstreams: Vec<UpstreamStatus>,
+}
+
+fn do_get_status(
+    req_sender: &tokio::sync::mpsc::Sender<StampRequest>,
+    upstreams: &Upstreams,
+    info: &ServerInfo,
+) -> Response<Full<Bytes>> {
+    let config = &info.batch_config;
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION"),
+        our_name: &info.our_name,
+        upstream_calendar_name: &info.upstream_calendar_name,
+        period: config.period.as_secs_f64(),
+        min_period: config.min_period.as_secs_f64(),
+        max_period: config.max_period.as_secs_f64(),
+        current_period: config.period(upstreams.latency()).as_secs_f64(),
+        queue_depth: req_sender.max_capacity() - req_sender.capacity(),
+        queue_capacity: req_sender.max_capacity(),
+        rounds: upstreams.rounds(),
+        uptime: info.started.elapsed().as_secs_f64(),
+        upstreams: upstreams.status(),
+    };
+    let body = serde_json::to_vec_pretty(&status).expect("status serializes");
+    Response::builder()
+             .status(StatusCode::OK)
+             .header(http::header::CONTENT_TYPE, "application/json")
+             .header(http::header::CACHE_CONTROL, "no-store")
+             .body(Full::new(Bytes::from(body)))
+             .unwrap()
+}
+
+async fn do_get_timestamp(
+    request_id: RequestId,
+    commitment: &str,
+    if_none_match: Option<&http::HeaderValue>,
+    upstreams: &Upstreams,
+    timestamp_proxy: &TimestampProxy,
+) -> Response<Full<Bytes>> {
+    let Some(commitment) = proxy::parse_commitment(commitment) else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_commitment", "invalid commitment", request_id);
+    };
+    let upgrade = match timestamp_proxy.get(upstreams, &commitment).await {
+        Ok(upgrade) => upgrade,
+        Err(err) => {
+            log::warn!("request {}: {}", request_id, std::error::Report::new(&*err));
+            return upstream_error_response(&err, request_id);
+        },
+    };
+
+    let cache_control = format!("public, max-age={}", upgrade.max_age.as_secs());
+    match (upgrade.proof, upgrade.etag) {
+        (Some(proof), Some(etag)) => {
+            let not_modified = if_none_match.and_then(|value| value.to_str().ok())
+                                            .is_some_and(|value| proxy::etag_matches(value, &etag));
+            let response = Response::builder()
+                                    .header(http::header::ETAG, etag)
+                                    .header(http::header::CACHE_CONTROL, cache_control);
+            if not_modified {
+                response.status(StatusCode::NOT_MODIFIED)
+                        .body(Full::new(Bytes::new()))
+                        .unwrap()
+            } else {
+                response.status(StatusCode::OK)
+                        .header(http::header::CONTENT_TYPE, "application/vnd.opentimestamps.v1")
+                        .body(Full::new(proof))
+                        .unwrap()
+            }
+        },
+        _ => {
+            let mut response = error_response(StatusCode::NOT_FOUND, "not_found", "not found", request_id);
+            response.headers_mut().insert(http::header::CACHE_CONTROL,
+                                          cache_control.parse().expect("max-age is a valid header value"));
+            response
+        },
+    }
+}
+
+fn health_response(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+             .status(status)
+             .header(http::header::CONTENT_TYPE, "text/plain")
+             .header(http::header::CACHE_CONTROL, "no-store")
+             .body(Full::new(Bytes::from(body)))
+             .unwrap()
+}
+
+/// Liveness: the aggregator task is still receiving requests.
+fn do_get_healthz(req_sender: &tokio::sync::mpsc::Sender<StampRequest>) -> Response<Full<Bytes>> {
+    if req_sender.is_closed() {
+        health_response(StatusCode::SERVICE_UNAVAILABLE, "aggregator task is not running\n".to_string())
+    } else {
+        health_response(StatusCode::OK, "ok\n".to_string())
+    }
+}
+
+/// Readiness: we can currently get requests timestamped.
+fn do_get_readyz(
+    req_sender: &tokio::sync::mpsc::Sender<StampRequest>,
+    upstreams: &Upstreams,
+    info: &ServerInfo,
+) -> Response<Full<Bytes>> {
+    match info.readiness.check(req_sender, upstreams) {
+        Ok(()) => health_response(StatusCode::OK, "ok\n".to_string()),
+        Err(reason) => health_response(StatusCode::SERVICE_UNAVAILABLE, format!("{}\n", reason)),
+    }
+}
+
+fn do_rate_limited(retry_after: Duration, request_id: RequestId) -> Response<Full<Bytes>> {
+    with_retry_after(error_response(StatusCode::TOO_MANY_REQUESTS, "rate_limited", "too many requests", request_id),
+                     retry_after)
+}
+
+/// Maximum length of a submitted digest, in bytes.
+const MAX_DIGEST_LEN: usize = 64;
+
 async fn do_post_digest(
+    request_id: RequestId,
     r: Request<hyper::body::Incoming>,
     req_sender: tokio::sync::mpsc::Sender<StampRequest>,
+    upstreams: Arc<Upstreams>,
+    info: &ServerInfo,
 )
     -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>>
 {
-    let digest_fut = Limited::new(r.into_body(), 64)
+    // Fail fast while upstream is known to be down, so clients can try another aggregator.
+    if let Err(retry_after) = upstreams.available() {
+        return Ok(with_retry_after(error_response(StatusCode::SERVICE_UNAVAILABLE, "circuit_open",
+                                                  "upstream calendar unavailable", request_id),
+                                   retry_after));
+    }
+
+    let digest_fut = Limited::new(r.into_body(), MAX_DIGEST_LEN)
                              .collect();
 
     match digest_fut.await {
         Ok(digest) => {
             let digest = digest.to_bytes();
+            if digest.is_empty() {
+                return Ok(error_response(StatusCode::BAD_REQUEST, "empty_digest", "empty digest", request_id));
+            }
 
             let (req, timestamp_receiver) = StampRequest::new(&digest);
-            req_sender.send(req).await?;
+            let full = match info.queue_full_wait {
+                None => {
+                    req_sender.send(req).await?;
+                    false
+                },
+                Some(wait) if wait.is_zero() => match req_sender.try_send(req) {
+                    Ok(()) => false,
+                    Err(TrySendError::Full(_)) => true,
+                    Err(err @ TrySendError::Closed(_)) => return Err(err.into()),
+                },
+                Some(wait) => match req_sender.send_timeout(req, wait).await {
+                    Ok(()) => false,
+                    Err(SendTimeoutError::Timeout(_)) => true,
+                    Err(err @ SendTimeoutError::Closed(_)) => return Err(err.into()),
+                },
+            };
+            if full {
+                // The queue drains roughly once per batching period.
+                metrics::inc(&METRICS.queue_full);
+                let retry_after = info.batch_config.period(upstreams.latency());
+                return Ok(with_retry_after(error_response(StatusCode::SERVICE_UNAVAILABLE, "queue_full",
+                                                          "aggregation queue full", request_id),
+                                           retry_after));
+            }
 
             match timestamp_receiver.await? {
                 Ok(stamp) => {
@@ -66,24 +358,17 @@ async fn do_post_digest(
                                 .unwrap())
                 },
                 Err(err) => {
-                    // FIXME: is having urls here potentially a security risk?
-                    let body = format!("internal error: {}\n", &err);
-                    Ok(Response::builder()
-                                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                                .header(http::header::CONTENT_TYPE, "text/plain")
-                                .body(Full::new(Bytes::from(body)))
-                                .unwrap())
+                    // The error itself is logged once for the whole batch.
+                    log::debug!("request {}: batch {} failed", request_id, err.batch_id);
+                    Ok(upstream_error_response(&err.error, request_id))
                 },
             }
         },
         Err(e) => {
             match e.downcast::<LengthLimitError>() {
                 Ok(_) => {
-                    Ok(Response::builder()
-                                .status(StatusCode::BAD_REQUEST) // should actually be 413 Payload Too Large
-                                .header(http::header::CONTENT_TYPE, "text/plain")
-                                .body(Full::new(Bytes::from("digest too long\n")))
-                                .unwrap())
+                    let message = format!("digest longer than {} bytes", MAX_DIGEST_LEN);
+                    Ok(error_response(StatusCode::PAYLOAD_TOO_LARGE, "too_large", &message, request_id))
                 },
                 // FIXME: what exactly does an error here mean?
                 Err(e) => Err(e),
@@ -92,39 +377,151 @@ async fn do_post_digest(
     }
 }
 
+/// The paths we serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    Root,
+    Favicon,
+    Metrics,
+    Status,
+    Healthz,
+    Readyz,
+    Timestamp,
+    Digest,
+}
+
+impl Route {
+    fn from_path(path: &str) -> Option<Self> {
+        Some(match path {
+            "/"            => Self::Root,
+            "/favicon.ico" => Self::Favicon,
+            "/metrics"     => Self::Metrics,
+            "/status"      => Self::Status,
+            "/healthz"     => Self::Healthz,
+            "/readyz"      => Self::Readyz,
+            "/digest"      => Self::Digest,
+            path if path.starts_with("/timestamp/") => Self::Timestamp,
+            _ => return None,
+        })
+    }
+
+    /// Methods the route supports, as listed in `Allow`; HEAD goes wherever GET does.
+    fn allow(self) -> &'static str {
+        match self {
+            Self::Digest => "POST",
+            _ => "GET, HEAD",
+        }
+    }
+
+    fn allows(self, method: &http::Method) -> bool {
+        match self {
+            Self::Digest => method == http::Method::POST,
+            _ => method == http::Method::GET || method == http::Method::HEAD,
+        }
+    }
+
+    /// Label for metrics.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Root      => "/",
+            Self::Favicon   => "/favicon.ico",
+            Self::Metrics   => "/metrics",
+            Self::Status    => "/status",
+            Self::Healthz   => "/healthz",
+            Self::Readyz    => "/readyz",
+            Self::Timestamp => "/timestamp",
+            Self::Digest    => "/digest",
+        }
+    }
+}
+
+fn do_not_found(request_id: RequestId) -> Response<Full<Bytes>> {
+    let mut response = error_response(StatusCode::NOT_FOUND, "not_found", "not found", request_id);
+    response.headers_mut().insert(http::header::CACHE_CONTROL, http::HeaderValue::from_static("public, max-age=3600"));
+    response
+}
+
+fn do_method_not_allowed(allow: &'static str, request_id: RequestId) -> Response<Full<Bytes>> {
+    let mut response = error_response(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", "method not allowed", request_id);
+    response.headers_mut().insert(http::header::ALLOW, http::HeaderValue::from_static(allow));
+    response
+}
+
 async fn serve_http_request(
     r: Request<hyper::body::Incoming>,
     digest_sender: tokio::sync::mpsc::Sender<StampRequest>,
-    our_name: String,
-    upstream_name: String,
+    upstreams: Arc<Upstreams>,
+    timestamp_proxy: Arc<TimestampProxy>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    remote_addr: SocketAddr,
+    info: Arc<ServerInfo>,
 ) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error + Send + Sync>> {
-    log::debug!("{:?}", r);
-    match (r.method(), r.uri().path()) {
-        (&http::Method::GET,  "/")            => Ok(do_get_root(our_name, upstream_name)),
-        (&http::Method::GET,  "/favicon.ico") => Ok(do_get_favicon()),
-        (&http::Method::POST, "/digest")      => Ok(do_post_digest(r, digest_sender).await?),
-        _ => { // FIXME: distinguish methods being invalid (GET-vs-POST) and not found
-            Ok(Response::builder()
-                        .header(http::header::CONTENT_TYPE, "text/plain")
-                        .header(http::header::CACHE_CONTROL, "public, max-age=3600")
-                        .status(StatusCode::NOT_FOUND)
-                        .body(Full::new(Bytes::from("Not found\n")))?)
-        }
+    let request_id = RequestId::new();
+    log::debug!("request {}: {:?}", request_id, r);
+    let route = Route::from_path(r.uri().path());
+    let head = r.method() == http::Method::HEAD;
+    let result = match route {
+        None => Ok(do_not_found(request_id)),
+        Some(route) if !route.allows(r.method()) => Ok(do_method_not_allowed(route.allow(), request_id)),
+        Some(Route::Root)    => Ok(do_get_root(&info)),
+        Some(Route::Favicon) => Ok(do_get_favicon()),
+        Some(Route::Metrics) => Ok(do_get_metrics(&digest_sender)),
+        Some(Route::Status)  => Ok(do_get_status(&digest_sender, &upstreams, &info)),
+        Some(Route::Healthz) => Ok(do_get_healthz(&digest_sender)),
+        Some(Route::Readyz)  => Ok(do_get_readyz(&digest_sender, &upstreams, &info)),
+        Some(Route::Timestamp) => {
+            let commitment = &r.uri().path()["/timestamp/".len() ..];
+            let if_none_match = r.headers().get(http::header::IF_NONE_MATCH);
+            Ok(do_get_timestamp(request_id, commitment, if_none_match, &upstreams, &timestamp_proxy).await)
+        },
+        Some(Route::Digest) => {
+            let limited = rate_limiter.and_then(|limiter| {
+                let client = limiter.client_ip(remote_addr.ip(), r.headers());
+                limiter.check(client).inspect_err(|_| log::debug!("rate limiting {}", client)).err()
+            });
+            match limited {
+                Some(retry_after) => {
+                    metrics::inc(&METRICS.rate_limited);
+                    Ok(do_rate_limited(retry_after, request_id))
+                },
+                None => do_post_digest(request_id, r, digest_sender, upstreams, &info).await,
+            }
+        },
+    };
+    let mut response = result.unwrap_or_else(|err| {
+        log::error!("request {}: {}", request_id, std::error::Report::new(&*err).pretty(true));
+        error_response(StatusCode::INTERNAL_SERVER_ERROR, "internal", "internal error", request_id)
+    });
+    response.headers_mut().insert(REQUEST_ID, request_id.to_string().parse().expect("hex is a valid header value"));
+    if head {
+        let len = hyper::body::Body::size_hint(response.body()).exact().expect("full bodies have an exact size");
+        response.headers_mut().insert(http::header::CONTENT_LENGTH, len.into());
+        *response.body_mut() = Full::new(Bytes::new());
     }
+
+    // Unknown paths are lumped together to keep the number of metric labels bounded.
+    METRICS.http_requests.inc((route.map_or("other", Route::name), response.status().as_u16()));
+    Ok(response)
 }
 
 pub struct RPCService {
     request_sender: tokio::sync::mpsc::Sender<StampRequest>,
-    our_name: String,
-    upstream_calendar_name: String,
+    upstreams: Arc<Upstreams>,
+    timestamp_proxy: Arc<TimestampProxy>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    remote_addr: SocketAddr,
+    info: Arc<ServerInfo>,
 }
 
 impl RPCService {
     pub fn new(request_sender: tokio::sync::mpsc::Sender<StampRequest>,
-               our_name: String,
-               upstream_calendar_name: String,
+               upstreams: Arc<Upstreams>,
+               timestamp_proxy: Arc<TimestampProxy>,
+               rate_limiter: Option<Arc<RateLimiter>>,
+               remote_addr: SocketAddr,
+               info: Arc<ServerInfo>,
                ) -> Self {
-        Self { request_sender, our_name, upstream_calendar_name }
+        Self { request_sender, upstreams, timestamp_proxy, rate_limiter, remote_addr, info }
     }
 }
 
@@ -138,8 +535,211 @@ impl Service<Request<hyper::body::Incoming>> for RPCService {
                 serve_http_request(
                     req,
                     self.request_sender.clone(),
-                    self.our_name.clone(),
-                    self.upstream_calendar_name.clone(),
+                    Arc::clone(&self.upstreams),
+                    Arc::clone(&self.timestamp_proxy),
+                    self.rate_limiter.clone(),
+                    self.remote_addr,
+                    Arc::clone(&self.info),
                 )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::server::conn::http1;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use crate::aggregator::{self, BatchOverflow};
+    use crate::trees::TreeShape;
+    use crate::mock_calendar::{MockCalendar, Reply};
+    use crate::proxy::CachePolicy;
+    use crate::upstream::UpstreamsConfig;
+
+    /// Starts an aggregator for the calendar on a random loopback port, returning its URL.
+    async fn start_server(calendar: &MockCalendar) -> String {
+        let upstreams = Arc::new(Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap());
+        let period = Duration::from_millis(10);
+        let batch_config = BatchConfig {
+            period,
+            min_period: period,
+            max_period: period,
+            target_batch_size: None,
+            max_batch_size: None,
+            overflow: BatchOverflow::Split,
+            tree_shape: TreeShape::Duplicate,
+            max_inflight_batches: 16,
+        };
+        let (request_sender, request_receiver) = tokio::sync::mpsc::channel(16);
+        tokio::task::spawn(aggregator::aggregator_task(request_receiver, batch_config.clone(), Arc::clone(&upstreams)));
+
+        let info = Arc::new(ServerInfo {
+            our_name: "test".to_string(),
+            upstream_calendar_name: calendar.url().to_string(),
+            batch_config,
+            started: Instant::now(),
+            readiness: Readiness {
+                max_failing: Duration::from_secs(60),
+                max_queue_fill: 0.9,
+            },
+            queue_full_wait: None,
+        });
+        let timestamp_proxy = Arc::new(TimestampProxy::new(CachePolicy::default()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, remote_addr) = listener.accept().await.unwrap();
+                let service = RPCService::new(request_sender.clone(), Arc::clone(&upstreams), Arc::clone(&timestamp_proxy),
+                                              None, remote_addr, Arc::clone(&info));
+                tokio::task::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_post_digest() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let url = start_server(&calendar).await;
+
+        let response = reqwest::Client::new().post(format!("{url}/digest")).body(vec![0; 32]).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "application/vnd.opentimestamps.v1");
+        let stamp = response.bytes().await.unwrap();
+        assert!(stamp.ends_with(&calendar.proof()));
+    }
+
+    #[tokio::test]
+    async fn test_post_digest_bad_length() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let url = start_server(&calendar).await;
+        let client = reqwest::Client::new();
+
+        let response = client.post(format!("{url}/digest")).body(vec![0; MAX_DIGEST_LEN + 1]).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(response.text().await.unwrap().contains("\ncode: too_large\n"));
+
+        let response = client.post(format!("{url}/digest")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let request_id = response.headers()[REQUEST_ID].to_str().unwrap().to_string();
+        assert_eq!(response.text().await.unwrap(), format!("empty digest\ncode: empty_digest\nrequest id: {request_id}\n"));
+
+        let response = client.get(format!("{url}/timestamp/zz")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(response.text().await.unwrap().contains("\ncode: invalid_commitment\n"));
+
+        let response = client.post(format!("{url}/digest")).body(vec![0; MAX_DIGEST_LEN]).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(calendar.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_post_digest_upstream_error() {
+        let calendar = MockCalendar::start(Reply::Status(StatusCode::INTERNAL_SERVER_ERROR)).await;
+        let url = start_server(&calendar).await;
+
+        let response = reqwest::Client::new().post(format!("{url}/digest")).body(vec![0; 32]).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let request_id = response.headers()[REQUEST_ID].to_str().unwrap().to_string();
+        assert_eq!(request_id.len(), 16);
+
+        // No upstream URL or status in the body
+        let body = response.text().await.unwrap();
+        assert_eq!(body, format!("upstream calendar failed\ncode: bad_status\nrequest id: {request_id}\n"));
+    }
+
+    #[tokio::test]
+    async fn test_not_found() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let url = start_server(&calendar).await;
+
+        let response = reqwest::get(format!("{url}/nonexistent")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[http::header::CACHE_CONTROL], "public, max-age=3600");
+        let request_id = response.headers()[REQUEST_ID].to_str().unwrap().to_string();
+        let body = response.text().await.unwrap();
+        assert_eq!(body, format!("not found\ncode: not_found\nrequest id: {request_id}\n"));
+
+        let response = reqwest::get(format!("{url}/timestamp/zz")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // A commitment the upstream calendar doesn't have
+        let calendar = MockCalendar::start(Reply::Status(StatusCode::NOT_FOUND)).await;
+        let url = start_server(&calendar).await;
+        let response = reqwest::get(format!("{url}/timestamp/00")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(response.headers()[http::header::CACHE_CONTROL].to_str().unwrap().starts_with("public, max-age="));
+        assert!(response.text().await.unwrap().contains("code: not_found\n"));
+    }
+
+    #[tokio::test]
+    async fn test_method_not_allowed() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let url = start_server(&calendar).await;
+        let client = reqwest::Client::new();
+
+        let response = client.get(format!("{url}/digest")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[http::header::ALLOW], "POST");
+        assert!(response.text().await.unwrap().contains("code: method_not_allowed\n"));
+
+        for path in ["/", "/status", "/timestamp/00"] {
+            let response = client.post(format!("{url}{path}")).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(response.headers()[http::header::ALLOW], "GET, HEAD");
+        }
+        assert_eq!(calendar.hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let url = start_server(&calendar).await;
+        let client = reqwest::Client::new();
+
+        let response = client.head(format!("{url}/healthz")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_LENGTH], "3");
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        let response = client.head(format!("{url}/status")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "application/json");
+        assert!(response.bytes().await.unwrap().is_empty());
+
+        let response = client.head(format!("{url}/digest")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[http::header::ALLOW], "POST");
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let calendar = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        let readiness = Readiness {
+            max_failing: Duration::from_millis(50),
+            max_queue_fill: 0.5,
+        };
+        let (sender, receiver) = tokio::sync::mpsc::channel(4);
+        assert_eq!(readiness.check(&sender, &upstreams), Ok(()));
+
+        // Briefly failing upstream is tolerated
+        upstreams.submit([0; 32]).await.unwrap_err();
+        assert_eq!(readiness.check(&sender, &upstreams), Ok(()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(readiness.check(&sender, &upstreams).unwrap_err().contains("failing"));
+
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig::default()).unwrap();
+        for i in 0 .. 3 {
+            sender.try_send(StampRequest::new(&[i]).0).unwrap();
+        }
+        assert!(readiness.check(&sender, &upstreams).unwrap_err().contains("saturated"));
+
+        drop(receiver);
+        assert!(readiness.check(&sender, &upstreams).unwrap_err().contains("not running"));
+    }
+}
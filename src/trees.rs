@@ -1,4 +1,5 @@
 use bitcoin_hashes::Sha256;
+use rayon::prelude::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Op {
@@ -8,51 +9,63 @@ pub enum Op {
 }
 
 fn sha256_leaf(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
-    Sha256::hash_byte_chunks(&[left, right]).to_byte_array()
+    Sha256::hash_byte_chunks([left, right]).to_byte_array()
 }
 
-fn hash_pairs(mut digests: &[[u8; 32]]) -> Vec<[u8; 32]> {
-    let mut r = Vec::with_capacity(digests.len() / 2);
-    loop {
-        if let Some((left, rest)) = digests.split_first() {
-            if let Some((right, new_digests)) = rest.split_first() {
-                digests = new_digests;
-                r.push(sha256_leaf(left, right));
-            } else {
-                digests = &[];
-                r.push(sha256_leaf(left, left));
-            }
-        } else {
-            break r;
-        }
-    }
+/// What to do with the odd node at the end of a level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TreeShape {
+    /// Hash it with itself, as Bitcoin does, so every path has one step per level.
+    #[default]
+    Duplicate,
+
+    /// Promote it unchanged to the next level, saving a step in its paths.
+    CarryUp,
 }
 
-pub fn hash_tree(digests: &[[u8; 32]]) -> (Vec<Vec<Op>>, [u8; 32]) {
-    assert!(digests.len() > 0);
+/// Trees with at least this many digests have their paths read off on the rayon worker pool.
+const PARALLEL_THRESHOLD: usize = 1 << 14;
+
+fn hash_pairs(digests: &[[u8; 32]], shape: TreeShape) -> Vec<[u8; 32]> {
+    digests.chunks(2).map(|pair| match (pair, shape) {
+        ([left, right], _) => sha256_leaf(left, right),
+        // Odd-numbered hash
+        ([left], TreeShape::Duplicate) => sha256_leaf(left, left),
+        ([left], TreeShape::CarryUp) => *left,
+        _ => unreachable!(),
+    }).collect()
+}
+
+/// Builds the tree over all digests at once, returning the path from each digest to the tip.
+///
+/// The aggregator uses `TreeBuilder`; this is the straightforward version it is checked against.
+pub fn hash_tree(digests: &[[u8; 32]], shape: TreeShape) -> (Vec<Vec<Op>>, [u8; 32]) {
+    assert!(!digests.is_empty());
 
     let mut prev_level = digests;
     let mut inner_levels = vec![];
 
     while prev_level.len() > 1 {
-        inner_levels.push(hash_pairs(prev_level));
+        inner_levels.push(hash_pairs(prev_level, shape));
         prev_level = inner_levels.last().expect("we just pushed a level");
     }
 
     let mut levels: Vec<&[[u8; 32]]> = vec![digests];
     levels.extend(inner_levels.iter().map(|v| v.as_slice()));
 
-    let mut r = vec![];
-    for i in 0 .. digests.len() {
+    let path = |i: usize| {
         let mut steps = vec![];
         for j in 0 .. levels.len() - 1 {
             match (i >> j) & 0b1 {
                 0 => {
                     if let Some(sibling) = levels[j].get((i >> j) + 1) {
                         steps.push(Op::Append(*sibling));
-                    } else {
+                    } else if shape == TreeShape::Duplicate {
                         // Odd-numbered hash, duplicated.
                         steps.push(Op::Append(levels[j][i >> j]));
+                    } else {
+                        // Odd-numbered hash, carried up as is.
+                        continue;
                     }
                 },
                 1 => {
@@ -62,8 +75,9 @@ pub fn hash_tree(digests: &[[u8; 32]]) -> (Vec<Vec<Op>>, [u8; 32]) {
             };
             steps.push(Op::Sha256);
         }
-        r.push(steps);
-    }
+        steps
+    };
+    let r = (0 .. digests.len()).map(path).collect();
     (r, levels.last().unwrap()[0])
 }
 
@@ -72,33 +86,193 @@ pub fn hash_tree(digests: &[[u8; 32]]) -> (Vec<Vec<Op>>, [u8; 32]) {
 // a b c d
 // 0 1 2 3
 
+/// Link from a node to its parent: the op to apply to the node's digest before hashing.
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    parent: usize,
+    op: Op,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Node {
+    digest: [u8; 32],
+    parent: Option<Link>,
+}
+
+/// Builds the same tree as `hash_tree`, one digest at a time.
+///
+/// Every complete subtree is hashed as soon as its last digest is pushed, so only the frontier
+/// of subtrees still waiting for a right sibling has to be combined when the tree is finished.
+/// Nodes are kept in an arena with links to their parents, from which the paths are read off.
+#[derive(Debug, Clone, Default)]
+pub struct TreeBuilder {
+    shape: TreeShape,
+
+    nodes: Vec<Node>,
+
+    /// Arena index of each leaf, in the order pushed.
+    leaves: Vec<usize>,
+
+    /// Per level, the root of a complete subtree waiting for its right sibling.
+    frontier: Vec<Option<usize>>,
+}
+
+impl TreeBuilder {
+    pub fn new(shape: TreeShape) -> Self {
+        Self {
+            shape,
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    fn alloc(&mut self, digest: [u8; 32]) -> usize {
+        self.nodes.push(Node { digest, parent: None });
+        self.nodes.len() - 1
+    }
+
+    fn join(&mut self, left: usize, right: usize) -> usize {
+        let (left_digest, right_digest) = (self.nodes[left].digest, self.nodes[right].digest);
+        let parent = self.alloc(sha256_leaf(&left_digest, &right_digest));
+        self.nodes[left].parent = Some(Link { parent, op: Op::Append(right_digest) });
+        self.nodes[right].parent = Some(Link { parent, op: Op::Prepend(left_digest) });
+        parent
+    }
+
+    /// Odd node at the end of a level, hashed with itself or carried up as is.
+    fn odd(&mut self, node: usize) -> usize {
+        if self.shape == TreeShape::CarryUp {
+            return node;
+        }
+        let digest = self.nodes[node].digest;
+        let parent = self.alloc(sha256_leaf(&digest, &digest));
+        self.nodes[node].parent = Some(Link { parent, op: Op::Append(digest) });
+        parent
+    }
+
+    pub fn push(&mut self, digest: [u8; 32]) {
+        let mut node = self.alloc(digest);
+        self.leaves.push(node);
+        for level in 0 .. {
+            if level == self.frontier.len() {
+                self.frontier.push(None);
+            }
+            match self.frontier[level].take() {
+                Some(left) => node = self.join(left, node),
+                None => {
+                    self.frontier[level] = Some(node);
+                    break;
+                },
+            }
+        }
+    }
+
+    /// Finishes the tree, returning the path from each digest to the tip, and the tip.
+    ///
+    /// The paths of large trees are computed in parallel.
+    pub fn finish(self) -> (Vec<Vec<Op>>, [u8; 32]) {
+        self.finish_with(PARALLEL_THRESHOLD)
+    }
+
+    fn finish_with(mut self, parallel_threshold: usize) -> (Vec<Vec<Op>>, [u8; 32]) {
+        assert!(!self.is_empty());
+
+        // The highest level of the frontier always holds a node, and everything below it is
+        // combined into a single right sibling for it.
+        let top = self.frontier.len() - 1;
+        let mut carry = None;
+        for level in 0 ..= top {
+            carry = match (self.frontier[level], carry) {
+                (Some(left), Some(right)) => Some(self.join(left, right)),
+                (None, Some(node)) => Some(self.odd(node)),
+                (Some(node), None) if level < top => Some(self.odd(node)),
+                (node, None) => node,
+            };
+        }
+        let tip = carry.expect("the frontier is not empty");
+
+        // Reading off the paths is most of the work of a large tree, and each is independent.
+        let nodes = &self.nodes;
+        let path = |&leaf: &usize| {
+            let mut steps = vec![];
+            let mut node = leaf;
+            while let Some(link) = nodes[node].parent {
+                steps.push(link.op);
+                steps.push(Op::Sha256);
+                node = link.parent;
+            }
+            steps
+        };
+        let paths = if self.leaves.len() >= parallel_threshold {
+            self.leaves.par_iter().map(path).collect()
+        } else {
+            self.leaves.iter().map(path).collect()
+        };
+        (paths, nodes[tip].digest)
+    }
+}
+
+impl Extend<[u8; 32]> for TreeBuilder {
+    fn extend<I: IntoIterator<Item = [u8; 32]>>(&mut self, digests: I) {
+        for digest in digests {
+            self.push(digest);
+        }
+    }
+}
+
+impl FromIterator<[u8; 32]> for TreeBuilder {
+    fn from_iter<I: IntoIterator<Item = [u8; 32]>>(digests: I) -> Self {
+        let mut builder = Self::default();
+        builder.extend(digests);
+        builder
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use proptest::prelude::*;
+
+    /// Distinct digests, numbered from zero.
+    fn numbered_digests(n: u32) -> Vec<[u8; 32]> {
+        (0 .. n).map(|i| {
+            let mut digest = [0; 32];
+            digest[.. 4].copy_from_slice(&i.to_le_bytes());
+            digest
+        }).collect()
+    }
+
     #[test]
     fn test_hash_pairs() {
-        assert_eq!(hash_pairs(&[]), Vec::<[u8; 32]>::new());
+        assert_eq!(hash_pairs(&[], TreeShape::Duplicate), Vec::<[u8; 32]>::new());
         assert_eq!(hash_pairs(&[[0; 32],
-                                [1; 32]]),
+                                [1; 32]], TreeShape::Duplicate),
                    &[[92, 133, 149, 95, 112, 146, 131, 236, 206, 43, 116, 241, 177, 85, 41, 24, 129, 159, 57, 9, 17, 129, 110, 123, 180, 102, 128, 90, 56, 171, 135, 243]]);
-        assert_eq!(hash_pairs(&[[0; 32]]),
+        assert_eq!(hash_pairs(&[[0; 32]], TreeShape::Duplicate),
                    &[[245, 165, 253, 66, 209, 106, 32, 48, 39, 152, 239, 110, 211, 9, 151, 155, 67, 0, 61, 35, 32, 217, 240, 232, 234, 152, 49, 169, 39, 89, 251, 75]]);
     }
 
     #[test]
     fn test_hash_tree() {
-        let (digest_steps, tip) = hash_tree(&[[0; 32]]);
+        let (digest_steps, tip) = hash_tree(&[[0; 32]], TreeShape::Duplicate);
         assert_eq!(digest_steps, vec![vec![]]);
         assert_eq!(tip, [0; 32]);
 
-        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32]]);
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32]], TreeShape::Duplicate);
         assert_eq!(digest_steps,
                    vec![vec![Op::Append([1; 32]), Op::Sha256],
                         vec![Op::Prepend([0; 32]), Op::Sha256]]);
         assert_eq!(tip, [92, 133, 149, 95, 112, 146, 131, 236, 206, 43, 116, 241, 177, 85, 41, 24, 129, 159, 57, 9, 17, 129, 110, 123, 180, 102, 128, 90, 56, 171, 135, 243]);
 
-        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32]]);
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32]], TreeShape::Duplicate);
         assert_eq!(digest_steps,
                    vec![vec![Op::Append([1; 32]), Op::Sha256,
                              Op::Append([248, 59, 51, 43, 228, 230, 165, 164, 177, 197, 106, 175, 109, 181, 38, 87, 218, 73, 94, 20, 152, 112, 5, 125, 133, 144, 171, 157, 122, 97, 103, 173]), Op::Sha256],
@@ -108,7 +282,7 @@ mod tests {
                              Op::Prepend([92, 133, 149, 95, 112, 146, 131, 236, 206, 43, 116, 241, 177, 85, 41, 24, 129, 159, 57, 9, 17, 129, 110, 123, 180, 102, 128, 90, 56, 171, 135, 243]), Op::Sha256]]);
         assert_eq!(tip, [109, 239, 207, 248, 67, 177, 45, 214, 132, 22, 37, 128, 195, 65, 6, 82, 131, 134, 158, 75, 46, 9, 234, 154, 39, 193, 157, 153, 116, 98, 165, 60]);
 
-        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32], [3; 32]]);
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32], [3; 32]], TreeShape::Duplicate);
         assert_eq!(digest_steps,
                    vec![vec![Op::Append([1; 32]), Op::Sha256,
                              Op::Append([39, 243, 47, 187, 250, 194, 251, 187, 206, 88, 177, 7, 82, 20, 75, 90, 116, 70, 212, 185, 30, 75, 169, 15, 253, 238, 48, 94, 145, 89, 128, 232]), Op::Sha256],
@@ -120,12 +294,109 @@ mod tests {
                              Op::Prepend([92, 133, 149, 95, 112, 146, 131, 236, 206, 43, 116, 241, 177, 85, 41, 24, 129, 159, 57, 9, 17, 129, 110, 123, 180, 102, 128, 90, 56, 171, 135, 243]), Op::Sha256]]);
         assert_eq!(tip, [211, 95, 81, 105, 147, 137, 218, 126, 236, 124, 229, 235, 2, 100, 12, 109, 49, 140, 245, 26, 227, 158, 202, 137, 11, 188, 123, 132, 236, 181, 218, 104]);
 
-        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32], [3; 32], [4; 32], [5; 32], [6; 32], [7;32], [8; 32]]);
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32], [3; 32], [4; 32], [5; 32], [6; 32], [7;32], [8; 32]], TreeShape::Duplicate);
         assert_eq!(tip, [2, 13, 235, 58, 9, 19, 117, 234, 116, 28, 73, 93, 142, 23, 15, 38, 132, 232, 87, 160, 158, 71, 203, 108, 180, 79, 99, 227, 168, 102, 58, 177]);
         assert_eq!(digest_steps.len(), 9);
 
-        let (digest_steps, tip) = hash_tree(&[[0; 32]; 10000]);
+        let (digest_steps, tip) = hash_tree(&[[0; 32]; 10000], TreeShape::Duplicate);
         assert_eq!(tip, [181, 141, 144, 15, 94, 24, 46, 60, 80, 239, 116, 150, 158, 161, 108, 119, 38, 197, 73, 117, 124, 194, 53, 35, 195, 105, 88, 125, 167, 41, 55, 132]);
         assert_eq!(digest_steps.len(), 10000);
     }
+
+    #[test]
+    fn test_hash_tree_carry_up() {
+        let shape = TreeShape::CarryUp;
+        assert_eq!(hash_pairs(&[[0; 32]], shape), &[[0; 32]]);
+
+        let (digest_steps, tip) = hash_tree(&[[0; 32]], shape);
+        assert_eq!(digest_steps, vec![vec![]]);
+        assert_eq!(tip, [0; 32]);
+
+        // The odd digest skips a level, rather than appending itself
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32]], shape);
+        assert_eq!(digest_steps,
+                   vec![vec![Op::Append([1; 32]), Op::Sha256, Op::Append([2; 32]), Op::Sha256],
+                        vec![Op::Prepend([0; 32]), Op::Sha256, Op::Append([2; 32]), Op::Sha256],
+                        vec![Op::Prepend([92, 133, 149, 95, 112, 146, 131, 236, 206, 43, 116, 241, 177, 85, 41, 24, 129, 159, 57, 9, 17, 129, 110, 123, 180, 102, 128, 90, 56, 171, 135, 243]), Op::Sha256]]);
+        assert_eq!(tip, [167, 242, 250, 217, 67, 144, 85, 53, 177, 12, 207, 99, 200, 50, 128, 46, 216, 78, 175, 251, 21, 228, 251, 107, 238, 134, 168, 23, 195, 94, 184, 51]);
+
+        // And may skip several
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32], [3; 32], [4; 32]], shape);
+        assert_eq!(digest_steps[4],
+                   vec![Op::Prepend([211, 95, 81, 105, 147, 137, 218, 126, 236, 124, 229, 235, 2, 100, 12, 109, 49, 140, 245, 26, 227, 158, 202, 137, 11, 188, 123, 132, 236, 181, 218, 104]), Op::Sha256]);
+        assert_eq!(tip, [36, 57, 55, 254, 145, 184, 175, 204, 247, 121, 81, 175, 78, 148, 108, 153, 62, 33, 207, 225, 52, 100, 79, 173, 21, 218, 48, 46, 240, 147, 174, 104]);
+
+        let (digest_steps, tip) = hash_tree(&[[0; 32], [1; 32], [2; 32], [3; 32], [4; 32], [5; 32], [6; 32], [7;32], [8; 32]], shape);
+        assert_eq!(tip, [58, 146, 117, 64, 93, 158, 110, 10, 153, 80, 203, 68, 160, 214, 127, 208, 110, 53, 114, 76, 208, 87, 177, 129, 140, 36, 169, 103, 39, 140, 225, 26]);
+        assert_eq!(digest_steps[8].len(), 2);
+    }
+
+    #[test]
+    fn test_tree_builder() {
+        for shape in [TreeShape::Duplicate, TreeShape::CarryUp] {
+            for n in (1 ..= 70).chain([1000, 1025]) {
+                let digests = numbered_digests(n);
+                let mut builder = TreeBuilder::new(shape);
+                builder.extend(digests.iter().copied());
+                assert_eq!(builder.len(), n as usize);
+                assert_eq!(builder.clone().finish_with(0), hash_tree(&digests, shape), "{} digests, {:?}", n, shape);
+                assert_eq!(builder.finish_with(usize::MAX), hash_tree(&digests, shape), "{} digests, {:?}", n, shape);
+            }
+        }
+    }
+
+    /// Replays each path from its digest, checking each reaches the tip, has one step per level,
+    /// and only the odd node at the end of a level is duplicated or carried up, as per the shape.
+    fn check_paths(digests: &[[u8; 32]], shape: TreeShape, paths: &[Vec<Op>], tip: [u8; 32]) {
+        assert_eq!(paths.len(), digests.len());
+        let depth = digests.len().next_power_of_two().trailing_zeros() as usize;
+
+        for (i, (digest, path)) in digests.iter().zip(paths).enumerate() {
+            let mut steps = path.chunks(2);
+            let mut msg = *digest;
+            for level in 0 .. depth {
+                let width = digests.len().div_ceil(1 << level);
+                let index = i >> level;
+                let odd_one_out = index == width - 1 && width % 2 == 1;
+                if odd_one_out && shape == TreeShape::CarryUp {
+                    continue;
+                }
+                msg = match *steps.next().expect("a step per level") {
+                    [Op::Append(sibling), Op::Sha256] => {
+                        assert!(index % 2 == 0);
+                        if odd_one_out {
+                            assert_eq!(sibling, msg);
+                        }
+                        sha256_leaf(&msg, &sibling)
+                    },
+                    [Op::Prepend(sibling), Op::Sha256] => {
+                        assert!(index % 2 == 1);
+                        sha256_leaf(&sibling, &msg)
+                    },
+                    ref step => panic!("unexpected step {:?}", step),
+                };
+            }
+            assert_eq!(steps.next(), None);
+            assert_eq!(msg, tip);
+        }
+    }
+
+    fn any_shape() -> impl Strategy<Value = TreeShape> {
+        prop_oneof![Just(TreeShape::Duplicate), Just(TreeShape::CarryUp)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_hash_tree_paths(digests in prop::collection::vec(any::<[u8; 32]>(), 1 .. 300), shape in any_shape()) {
+            let (paths, tip) = hash_tree(&digests, shape);
+            check_paths(&digests, shape, &paths, tip);
+        }
+
+        #[test]
+        fn prop_tree_builder_matches(digests in prop::collection::vec(any::<[u8; 32]>(), 1 .. 300), shape in any_shape()) {
+            let mut builder = TreeBuilder::new(shape);
+            builder.extend(digests.iter().copied());
+            prop_assert_eq!(builder.finish(), hash_tree(&digests, shape));
+        }
+    }
 }
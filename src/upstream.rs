@@ -0,0 +1,1092 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use hyper::body::Bytes;
+use reqwest::{StatusCode, Url};
+
+use crate::aggregator::StampRequestError;
+use crate::metrics::METRICS;
+use crate::ots;
+
+/// Order in which upstream calendars are tried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpstreamOrder {
+    /// Always try upstreams in the order given on the command line.
+    InOrder,
+
+    /// Try the healthiest upstreams first, ties broken by command line order.
+    Health,
+}
+
+/// Circuit breaker state of an upstream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Breaker {
+    /// Submissions are allowed.
+    #[default]
+    Closed,
+
+    /// Too many consecutive failures; no submissions until the cooldown is over.
+    Open {
+        until: Instant,
+    },
+
+    /// Cooldown is over; a single probe submission decides whether to close or re-open.
+    HalfOpen {
+        probing: bool,
+    },
+}
+
+/// When the circuit breaker around each upstream opens.
+#[derive(Debug, Clone)]
+pub struct BreakerPolicy {
+    /// Number of consecutive failed batches that opens the breaker.
+    pub threshold: u32,
+
+    /// How long the breaker stays open before a probe is let through.
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            cooldown: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    successes: u64,
+    failures: u64,
+    rejected_proofs: u64,
+    last_success: Option<Instant>,
+    breaker: Breaker,
+}
+
+impl Health {
+    /// Moves an open breaker whose cooldown is over to half-open.
+    fn update_breaker(&mut self) {
+        if let Breaker::Open { until } = self.breaker && Instant::now() >= until {
+            self.breaker = Breaker::HalfOpen { probing: false };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Upstream {
+    url: Url,
+
+    /// URI that pending attestations in proofs from this upstream must have.
+    calendar_uri: String,
+
+    health: Mutex<Health>,
+}
+
+impl Upstream {
+    /// Creates a new upstream; the calendar URI defaults to the origin of the URL.
+    pub fn new(url: Url, calendar_uri: Option<String>) -> Self {
+        Self {
+            calendar_uri: calendar_uri.unwrap_or_else(|| url.origin().ascii_serialization()),
+            url,
+            health: Mutex::new(Health::default()),
+        }
+    }
+
+    /// Health score; lower is healthier.
+    fn score(&self) -> u32 {
+        self.health.lock().unwrap().consecutive_failures
+    }
+
+    /// Remaining cooldown if the circuit breaker is open.
+    fn open_for(&self) -> Option<Duration> {
+        let mut health = self.health.lock().unwrap();
+        health.update_breaker();
+        match health.breaker {
+            Breaker::Open { until } => Some(until.saturating_duration_since(Instant::now())),
+            Breaker::Closed | Breaker::HalfOpen { .. } => None,
+        }
+    }
+
+    /// Whether the circuit breaker lets a submission through; if half-open, only one probe is.
+    fn try_begin_submit(&self) -> bool {
+        let mut health = self.health.lock().unwrap();
+        health.update_breaker();
+        match health.breaker {
+            Breaker::Closed => true,
+            Breaker::Open { .. } | Breaker::HalfOpen { probing: true } => false,
+            Breaker::HalfOpen { probing: false } => {
+                log::info!("probing upstream {}", self.url);
+                health.breaker = Breaker::HalfOpen { probing: true };
+                true
+            },
+        }
+    }
+
+    /// Gives up a submission allowed by `try_begin_submit` without making it, releasing the probe.
+    fn abandon_submit(&self) {
+        let mut health = self.health.lock().unwrap();
+        if health.breaker == (Breaker::HalfOpen { probing: true }) {
+            health.breaker = Breaker::HalfOpen { probing: false };
+        }
+    }
+
+    fn status(&self) -> UpstreamStatus {
+        let mut health = self.health.lock().unwrap();
+        health.update_breaker();
+        UpstreamStatus {
+            url: self.url.to_string(),
+            calendar_uri: self.calendar_uri.clone(),
+            breaker: match health.breaker {
+                Breaker::Closed => "closed",
+                Breaker::Open { .. } => "open",
+                Breaker::HalfOpen { .. } => "half-open",
+            },
+            consecutive_failures: health.consecutive_failures,
+            successes: health.successes,
+            failures: health.failures,
+            rejected_proofs: health.rejected_proofs,
+            last_success_age: health.last_success.map(|t| t.elapsed().as_secs_f64()),
+        }
+    }
+
+    fn record_success(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = 0;
+        health.successes += 1;
+        health.last_success = Some(Instant::now());
+        if health.breaker != Breaker::Closed {
+            log::info!("upstream {} recovered; closing circuit breaker", self.url);
+            health.breaker = Breaker::Closed;
+        }
+    }
+
+    fn record_failure(&self, breaker: &BreakerPolicy) {
+        let mut health = self.health.lock().unwrap();
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        health.failures += 1;
+        log::debug!("upstream {} has failed {} times in a row ({} successes, {} failures, {} rejected_proofs total, last success {:?} ago)",
+                    self.url, health.consecutive_failures, health.successes, health.failures, health.rejected_proofs,
+                    health.last_success.map(|t| t.elapsed()));
+
+        let reopen = matches!(health.breaker, Breaker::HalfOpen { .. });
+        if reopen || (health.breaker == Breaker::Closed && health.consecutive_failures >= breaker.threshold) {
+            log::warn!("opening circuit breaker for upstream {} for {:?}", self.url, breaker.cooldown);
+            health.breaker = Breaker::Open { until: Instant::now() + breaker.cooldown };
+        }
+    }
+
+    fn record_rejected_proof(&self) {
+        self.health.lock().unwrap().rejected_proofs += 1;
+    }
+
+    /// Submits the tip digest, retrying per the policy until success or the given deadline.
+    async fn submit_with_retry(&self,
+                               client: &reqwest::Client,
+                               tip_digest: [u8; 32],
+                               limits: ots::Limits,
+                               retry: &RetryPolicy,
+                               deadline: Instant,
+                               ) -> Result<Bytes, StampRequestError>
+    {
+        let mut attempt = 1;
+        loop {
+            let result = tokio::time::timeout_at(deadline.into(), self.submit(client, tip_digest, limits))
+                                     .await
+                                     .unwrap_or(Err(StampRequestError::DeadlineExceeded));
+            match result {
+                Err(err) if attempt < retry.max_attempts && retry.is_retryable(&err) => {
+                    let backoff = retry.backoff(attempt);
+                    if Instant::now() + backoff >= deadline {
+                        return Err(err);
+                    }
+                    log::info!("upstream {} failed: {}; retrying in {:?} (attempt {} of {})",
+                               self.url, err, backoff, attempt, retry.max_attempts);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    async fn submit(&self, client: &reqwest::Client, tip_digest: [u8; 32], limits: ots::Limits) -> Result<Bytes, StampRequestError> {
+        let mut response = client.post(self.url.clone())
+                                 .body(Vec::from(tip_digest))
+                                 .send().await?;
+        if response.status() == StatusCode::OK {
+            let proof = read_proof(&mut response, limits).await?;
+            log::debug!("got {} bytes of proof from upstream {}", proof.len(), self.url);
+
+            let stamp = ots::Timestamp::deserialize(&proof, limits)?;
+            for attestation in stamp.all_attestations() {
+                log::debug!("upstream {} proof attestation: {}", self.url, attestation);
+            }
+
+            if let Err(err) = stamp.verify_pending(&tip_digest, &self.calendar_uri) {
+                self.record_rejected_proof();
+                log::error!("upstream {} returned an unacceptable proof for tip {:x?}: {}",
+                            self.url, tip_digest, err);
+                return Err(StampRequestError::RejectedProof(err));
+            }
+            Ok(proof)
+        } else {
+            Err(StampRequestError::BadStatus(response.status()))
+        }
+    }
+
+    /// Fetches the timestamp for a commitment from the calendar, or `None` if it doesn't have one.
+    async fn get_timestamp(&self, client: &reqwest::Client, commitment: &str, limits: ots::Limits)
+        -> Result<Option<(Bytes, ots::Timestamp)>, StampRequestError>
+    {
+        let url = format!("{}/timestamp/{}", self.calendar_uri.trim_end_matches('/'), commitment);
+        let mut response = client.get(url).send().await?;
+        match response.status() {
+            StatusCode::OK => {
+                let proof = read_proof(&mut response, limits).await?;
+                let stamp = ots::Timestamp::deserialize(&proof, limits)?;
+                Ok(Some((proof, stamp)))
+            },
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(StampRequestError::BadStatus(status)),
+        }
+    }
+}
+
+/// Reads a proof from the response body, failing if it is larger than the limit.
+async fn read_proof(response: &mut reqwest::Response, limits: ots::Limits) -> Result<Bytes, StampRequestError> {
+    if let Some(len) = response.content_length().filter(|len| *len > limits.max_size as u64) {
+        return Err(ots::DeserializeError::TooLarge(len as usize, limits.max_size).into());
+    }
+
+    // Read the body chunk by chunk so an upstream can't make us buffer more than the limit.
+    let mut proof = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        proof.extend_from_slice(&chunk);
+        if proof.len() > limits.max_size {
+            return Err(ots::DeserializeError::TooLarge(proof.len(), limits.max_size).into());
+        }
+    }
+    Ok(Bytes::from(proof))
+}
+
+/// How failed upstream submissions are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts per upstream, including the first.
+    pub max_attempts: u32,
+
+    /// Backoff after the first failed attempt, doubling for each further attempt.
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+
+    /// Fraction of each backoff that is randomized, from 0 (none) to 1.
+    pub jitter: f64,
+
+    /// Status codes worth retrying; transport errors are always retried.
+    pub retryable_statuses: Vec<StatusCode>,
+
+    /// Total time allowed to get proofs for a batch, including all retries and failovers.
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            jitter: 0.5,
+            retryable_statuses: vec![StatusCode::BAD_GATEWAY,
+                                     StatusCode::SERVICE_UNAVAILABLE,
+                                     StatusCode::GATEWAY_TIMEOUT],
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn is_retryable(&self, err: &StampRequestError) -> bool {
+        match err {
+            StampRequestError::Upstream(_) => true,
+            StampRequestError::BadStatus(status) => self.retryable_statuses.contains(status),
+            _ => false,
+        }
+    }
+
+    /// Backoff after the given (1-based) failed attempt.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.base_backoff.saturating_mul(1 << (attempt - 1).min(31))
+                                       .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter * rand::random::<f64>())
+    }
+}
+
+/// Settings for the HTTP client shared by all upstream submissions.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Timeout for each request, from connecting until the response body is read.
+    pub timeout: Duration,
+
+    pub connect_timeout: Duration,
+
+    /// Extra headers sent with every request.
+    pub headers: reqwest::header::HeaderMap,
+
+    /// PEM files with CA certificates to trust in addition to the system roots.
+    pub ca_certs: Vec<PathBuf>,
+
+    /// PEM files with a client certificate chain and its PKCS#8 private key.
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            connect_timeout: Duration::from_secs(1),
+            headers: reqwest::header::HeaderMap::new(),
+            ca_certs: vec![],
+            client_cert: None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientConfigError {
+    #[error("could not read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("invalid CA certificate in {0}: {1}")]
+    CaCert(PathBuf, reqwest::Error),
+
+    #[error("invalid client certificate or key in {0}: {1}")]
+    ClientCert(PathBuf, reqwest::Error),
+
+    #[error("could not create HTTP client: {0}")]
+    Build(#[from] reqwest::Error),
+}
+
+impl ClientConfig {
+    pub fn build(&self) -> Result<reqwest::Client, ClientConfigError> {
+        let read = |path: &PathBuf| std::fs::read(path).map_err(|err| ClientConfigError::Read(path.clone(), err));
+
+        let mut builder = reqwest::Client::builder()
+            .user_agent(concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION")))
+            .default_headers(self.headers.clone())
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout)
+            .tcp_keepalive(Duration::from_secs(60));
+
+        for path in self.ca_certs.iter() {
+            let certs = reqwest::Certificate::from_pem_bundle(&read(path)?)
+                                             .map_err(|err| ClientConfigError::CaCert(path.clone(), err))?;
+            if certs.is_empty() {
+                return Err(ClientConfigError::Read(path.clone(), std::io::Error::other("no certificates found")));
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some((cert_path, key_path)) = &self.client_cert {
+            let identity = reqwest::Identity::from_pkcs8_pem(&read(cert_path)?, &read(key_path)?)
+                                             .map_err(|err| ClientConfigError::ClientCert(cert_path.clone(), err))?;
+            builder = builder.identity(identity);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// How tips are submitted to the upstream calendars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamMode {
+    /// Submit to one upstream at a time until one succeeds.
+    Failover(UpstreamOrder),
+
+    /// Submit to every upstream at once, succeeding if at least `quorum` of them do.
+    Fanout {
+        quorum: usize,
+    },
+}
+
+/// How tips are submitted to the upstream calendars, and what they may return.
+#[derive(Debug, Clone)]
+pub struct UpstreamsConfig {
+    pub mode: UpstreamMode,
+    pub limits: ots::Limits,
+    pub retry: RetryPolicy,
+    pub breaker: BreakerPolicy,
+    pub client: ClientConfig,
+}
+
+impl Default for UpstreamsConfig {
+    fn default() -> Self {
+        Self {
+            mode: UpstreamMode::Failover(UpstreamOrder::InOrder),
+            limits: ots::Limits::default(),
+            retry: RetryPolicy::default(),
+            breaker: BreakerPolicy::default(),
+            client: ClientConfig::default(),
+        }
+    }
+}
+
+/// The set of upstream calendars we can submit tips to.
+#[derive(Debug)]
+pub struct Upstreams {
+    upstreams: Vec<Upstream>,
+    mode: UpstreamMode,
+    limits: ots::Limits,
+    retry: RetryPolicy,
+    breaker: BreakerPolicy,
+    client: reqwest::Client,
+    latency: Mutex<Option<Duration>>,
+    rounds: Mutex<RoundStatus>,
+}
+
+/// Outcome of recent batches, as reported by `/status`.
+#[derive(Debug, Default, Clone, serde::Serialize)]
+pub struct RoundStatus {
+    /// When a batch last got its proofs, in seconds since the epoch.
+    pub last_success: Option<u64>,
+
+    pub last_error: Option<LastError>,
+
+    /// Start of the current run of failed batches.
+    #[serde(skip)]
+    failing_since: Option<Instant>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct LastError {
+    /// In seconds since the epoch.
+    pub at: u64,
+
+    /// Error code, as given to clients; the details, which name upstreams, are only logged.
+    pub error: &'static str,
+}
+
+/// Health of a single upstream, as reported by `/status`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct UpstreamStatus {
+    pub url: String,
+    pub calendar_uri: String,
+    pub breaker: &'static str,
+    pub consecutive_failures: u32,
+    pub successes: u64,
+    pub failures: u64,
+    pub rejected_proofs: u64,
+
+    /// Seconds since the last successful submission.
+    pub last_success_age: Option<f64>,
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |t| t.as_secs())
+}
+
+/// Weight of the latest batch in the upstream latency moving average.
+const LATENCY_EWMA_WEIGHT: f64 = 0.2;
+
+/// How long fanout waits for more proofs once it has a quorum.
+const FANOUT_GRACE: Duration = Duration::from_millis(100);
+
+impl From<Url> for Upstream {
+    fn from(url: Url) -> Self {
+        Self::new(url, None)
+    }
+}
+
+impl Upstreams {
+    /// Creates the set of upstreams; fails only if the HTTP client can't be built.
+    pub fn new(upstreams: impl IntoIterator<Item = impl Into<Upstream>>, config: UpstreamsConfig)
+        -> Result<Self, ClientConfigError>
+    {
+        let upstreams: Vec<Upstream> = upstreams.into_iter().map(Into::into).collect();
+        assert!(!upstreams.is_empty());
+        if let UpstreamMode::Fanout { quorum } = config.mode {
+            assert!(quorum >= 1 && quorum <= upstreams.len());
+        }
+        Ok(Self {
+            client: config.client.build()?,
+            upstreams,
+            mode: config.mode,
+            limits: config.limits,
+            retry: config.retry,
+            breaker: config.breaker,
+            latency: Mutex::new(None),
+            rounds: Mutex::new(RoundStatus::default()),
+        })
+    }
+
+    /// Upstreams in the order they should be tried.
+    fn candidates(&self, order: UpstreamOrder) -> Vec<&Upstream> {
+        let mut r: Vec<&Upstream> = self.upstreams.iter().collect();
+        match order {
+            UpstreamOrder::InOrder => {},
+            UpstreamOrder::Health => r.sort_by_key(|upstream| upstream.score()), // stable
+        }
+        r
+    }
+
+    fn record(&self, upstream: &Upstream, result: &Result<Bytes, StampRequestError>) {
+        match result {
+            Ok(_) => upstream.record_success(),
+            Err(err) => {
+                log::warn!("upstream {} failed: {}", upstream.url, std::error::Report::new(err));
+                METRICS.upstream_errors.inc((upstream.url.to_string(), err.name()));
+                upstream.record_failure(&self.breaker);
+            },
+        }
+    }
+
+    /// Checks whether enough upstreams have closed circuit breakers for a batch to succeed.
+    ///
+    /// If not, returns how long until one of them can be retried.
+    pub fn available(&self) -> Result<(), Duration> {
+        let open_for: Vec<Duration> = self.upstreams.iter().filter_map(Upstream::open_for).collect();
+        let needed = match self.mode {
+            UpstreamMode::Failover(_) => 1,
+            UpstreamMode::Fanout { quorum } => quorum,
+        };
+        if self.upstreams.len() - open_for.len() >= needed {
+            Ok(())
+        } else {
+            Err(open_for.into_iter().min().expect("at least one breaker is open"))
+        }
+    }
+
+    /// Submits the tip digest upstream, returning one proof per upstream that succeeded.
+    pub async fn submit(&self, tip_digest: [u8; 32]) -> Result<Vec<Bytes>, StampRequestError> {
+        let start = Instant::now();
+        let result = self.submit_before(tip_digest, start + self.retry.deadline).await;
+        METRICS.upstream_latency.observe(start.elapsed().as_secs_f64());
+        match &result {
+            Ok(_) => {
+                let mut latency = self.latency.lock().unwrap();
+                *latency = Some(match *latency {
+                    Some(latency) => latency.mul_f64(1.0 - LATENCY_EWMA_WEIGHT) + start.elapsed().mul_f64(LATENCY_EWMA_WEIGHT),
+                    None => start.elapsed(),
+                });
+                let mut rounds = self.rounds.lock().unwrap();
+                rounds.last_success = Some(unix_time());
+                rounds.failing_since = None;
+            },
+            Err(err) => {
+                let mut rounds = self.rounds.lock().unwrap();
+                rounds.last_error = Some(LastError { at: unix_time(), error: err.name() });
+                rounds.failing_since.get_or_insert_with(Instant::now);
+            },
+        }
+        result
+    }
+
+    /// Moving average of how long successful batches took to get their proofs.
+    pub fn latency(&self) -> Option<Duration> {
+        *self.latency.lock().unwrap()
+    }
+
+    /// Outcome of recent batches.
+    pub fn rounds(&self) -> RoundStatus {
+        self.rounds.lock().unwrap().clone()
+    }
+
+    /// How long every batch has been failing for, if the last one failed.
+    pub fn failing_for(&self) -> Option<Duration> {
+        self.rounds.lock().unwrap().failing_since.map(|since| since.elapsed())
+    }
+
+    /// Fetches the timestamp for a commitment from the first upstream calendar to reply with it.
+    ///
+    /// We don't know which upstream a commitment came from, so all those whose circuit breakers
+    /// aren't open are asked at once, and a slow or failing upstream doesn't hold up the others.
+    /// Returns `None` if none of them has it.
+    pub async fn get_timestamp(&self, commitment: &str) -> Result<Option<(Bytes, ots::Timestamp)>, StampRequestError> {
+        let mut requests: FuturesUnordered<_> = self.upstreams.iter()
+            .filter(|upstream| upstream.open_for().is_none())
+            .map(|upstream| async move {
+                (upstream, upstream.get_timestamp(&self.client, commitment, self.limits).await)
+            })
+            .collect();
+        if requests.is_empty() {
+            return Err(StampRequestError::CircuitOpen);
+        }
+
+        let mut last_err = None;
+        while let Some((upstream, result)) = requests.next().await {
+            match result {
+                Ok(Some(r)) => return Ok(Some(r)),
+                Ok(None) => {},
+                Err(err) => {
+                    log::warn!("getting timestamp {} from upstream {} failed: {}",
+                               commitment, upstream.calendar_uri, std::error::Report::new(&err));
+                    last_err = Some(err);
+                },
+            }
+        }
+        match last_err {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// Health of each upstream, in command line order.
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        self.upstreams.iter().map(Upstream::status).collect()
+    }
+
+    async fn submit_before(&self, tip_digest: [u8; 32], deadline: Instant) -> Result<Vec<Bytes>, StampRequestError> {
+        match self.mode {
+            UpstreamMode::Failover(order) => {
+                let candidates = self.candidates(order);
+                let mut last_err = None;
+                for (i, upstream) in candidates.iter().enumerate() {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        last_err = Some(StampRequestError::DeadlineExceeded);
+                        break;
+                    }
+                    if !upstream.try_begin_submit() {
+                        continue;
+                    }
+
+                    // Each upstream gets an equal share of the time left, so one that hangs doesn't
+                    // use it all up before the others are tried. This one isn't open, so at least one.
+                    let remaining = candidates[i ..].iter().filter(|upstream| upstream.open_for().is_none()).count();
+                    let upstream_deadline = now + deadline.saturating_duration_since(now) / remaining as u32;
+                    let result = upstream.submit_with_retry(&self.client, tip_digest, self.limits, &self.retry, upstream_deadline).await;
+                    self.record(upstream, &result);
+                    match result {
+                        Ok(proof) => return Ok(vec![proof]),
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                Err(last_err.unwrap_or(StampRequestError::CircuitOpen))
+            },
+            UpstreamMode::Fanout { quorum } => {
+                let upstreams: Vec<&Upstream> = self.upstreams.iter()
+                                                    .filter(|upstream| upstream.try_begin_submit())
+                                                    .collect();
+                if upstreams.len() < quorum {
+                    for upstream in upstreams {
+                        upstream.abandon_submit();
+                    }
+                    return Err(StampRequestError::CircuitOpen);
+                }
+
+                let mut pending: FuturesUnordered<_> = upstreams.iter().enumerate()
+                    .map(|(i, upstream)| async move {
+                        (i, upstream.submit_with_retry(&self.client, tip_digest, self.limits, &self.retry, deadline).await)
+                    })
+                    .collect();
+
+                // Once there's a quorum, stragglers only get a short grace period to add their proofs,
+                // so a slow upstream doesn't hold up the whole batch.
+                let mut results: Vec<Option<Result<Bytes, StampRequestError>>> = upstreams.iter().map(|_| None).collect();
+                let mut successes = 0;
+                let mut stop_at = deadline;
+                while successes + pending.len() >= quorum {
+                    let Ok(Some((i, result))) = tokio::time::timeout_at(stop_at.into(), pending.next()).await else {
+                        break;
+                    };
+                    self.record(upstreams[i], &result);
+                    if result.is_ok() {
+                        successes += 1;
+                        if successes == quorum {
+                            stop_at = stop_at.min(Instant::now() + FANOUT_GRACE);
+                        }
+                    }
+                    results[i] = Some(result);
+                }
+                drop(pending);
+
+                // Upstreams still submitting were only slower than the others, so aren't counted as
+                // failures; if half-open, they are probed again next time.
+                let mut proofs = vec![];
+                for (upstream, result) in upstreams.into_iter().zip(results) {
+                    match result {
+                        Some(Ok(proof)) => proofs.push(proof), // in command line order, so forks are deterministic
+                        Some(Err(_)) => {},
+                        None => upstream.abandon_submit(),
+                    }
+                }
+
+                if proofs.len() >= quorum {
+                    Ok(proofs)
+                } else {
+                    Err(StampRequestError::NoQuorum { successes: proofs.len(), quorum })
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mock_calendar::{MockCalendar, Reply, pending_proof};
+
+    fn new_upstreams(upstreams: impl IntoIterator<Item = impl Into<Upstream>>, mode: UpstreamMode) -> Upstreams {
+        Upstreams::new(upstreams, UpstreamsConfig { mode, ..UpstreamsConfig::default() }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let bad = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let good = MockCalendar::start(Reply::Proof).await;
+
+        let upstreams = new_upstreams([bad.url(), good.url()], UpstreamMode::Failover(UpstreamOrder::InOrder));
+        let proofs = upstreams.submit([0; 32]).await.unwrap();
+        assert_eq!(proofs, vec![good.proof()]);
+        assert_eq!(bad.hits(), 1);
+        assert_eq!(good.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_all_upstreams_fail() {
+        let bad1 = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let bad2 = MockCalendar::start(Reply::Status(StatusCode::SERVICE_UNAVAILABLE)).await;
+
+        let upstreams = new_upstreams([bad1.url(), bad2.url()], UpstreamMode::Failover(UpstreamOrder::InOrder));
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::BadStatus(StatusCode::SERVICE_UNAVAILABLE)));
+        assert_eq!(bad1.hits(), 1);
+        assert_eq!(bad2.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_order() {
+        let bad = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let good = MockCalendar::start(Reply::Proof).await;
+
+        let upstreams = new_upstreams([bad.url(), good.url()], UpstreamMode::Failover(UpstreamOrder::Health));
+        for _ in 0 .. 3 {
+            upstreams.submit([0; 32]).await.unwrap();
+        }
+
+        // Once the first upstream has failed it is tried last.
+        assert_eq!(bad.hits(), 1);
+        assert_eq!(good.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fanout() {
+        let good1 = MockCalendar::start(Reply::Proof).await;
+        let bad = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let good2 = MockCalendar::start(Reply::Proof).await;
+
+        let upstreams = new_upstreams([good1.url(), bad.url(), good2.url()], UpstreamMode::Fanout { quorum: 2 });
+        let proofs = upstreams.submit([0; 32]).await.unwrap();
+        assert_eq!(proofs, vec![good1.proof(), good2.proof()]);
+        assert_eq!((good1.hits(), bad.hits(), good2.hits()), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_fanout_slow_upstream() {
+        let slow = MockCalendar::start(Reply::Delay(Duration::from_secs(10))).await;
+        let good1 = MockCalendar::start(Reply::Proof).await;
+        let good2 = MockCalendar::start(Reply::Proof).await;
+
+        // The quorum is met without waiting for the slow upstream, which isn't counted as failing
+        let upstreams = new_upstreams([slow.url(), good1.url(), good2.url()], UpstreamMode::Fanout { quorum: 2 });
+        let start = Instant::now();
+        let proofs = upstreams.submit([0; 32]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(proofs, vec![good1.proof(), good2.proof()]);
+        assert_eq!(upstreams.upstreams[0].health.lock().unwrap().failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_fanout_no_quorum() {
+        let good = MockCalendar::start(Reply::Proof).await;
+        let bad = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+
+        let upstreams = new_upstreams([good.url(), bad.url()], UpstreamMode::Fanout { quorum: 2 });
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::NoQuorum { successes: 1, quorum: 2 }));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_proof() {
+        let calendar = MockCalendar::start(Reply::Body(Bytes::from_static(b"\x08\x08"))).await;
+
+        let upstreams = new_upstreams([calendar.url()], UpstreamMode::Failover(UpstreamOrder::InOrder));
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::InvalidProof(ots::DeserializeError::Truncated)));
+    }
+
+    #[tokio::test]
+    async fn test_proof_too_large() {
+        let calendar = MockCalendar::start(Reply::Proof).await;
+        let limits = ots::Limits {
+            max_size: calendar.proof().len() - 1,
+            ..ots::Limits::default()
+        };
+
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig { limits, ..UpstreamsConfig::default() }).unwrap();
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::InvalidProof(ots::DeserializeError::TooLarge(_, _))));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_proof() {
+        let calendar = MockCalendar::start(Reply::Body(pending_proof("http://example.com").into())).await;
+
+        let upstreams = new_upstreams([calendar.url()], UpstreamMode::Failover(UpstreamOrder::InOrder));
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::RejectedProof(ots::VerifyError::UnexpectedAttestation { .. })));
+        assert_eq!(upstreams.upstreams[0].health.lock().unwrap().rejected_proofs, 1);
+
+        // ...but an explicitly configured calendar URI is accepted
+        let upstream = Upstream::new(calendar.url(), Some("http://example.com".to_string()));
+        let upstreams = new_upstreams([upstream], UpstreamMode::Failover(UpstreamOrder::InOrder));
+        upstreams.submit([0; 32]).await.unwrap();
+    }
+
+    fn retrying(max_attempts: u32, deadline: Duration) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            deadline,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..retrying(10, Duration::from_secs(1))
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(10));
+        assert_eq!(policy.backoff(2), Duration::from_millis(20));
+        assert_eq!(policy.backoff(3), Duration::from_millis(20));
+        assert_eq!(policy.backoff(100), Duration::from_millis(20));
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0 .. 100 {
+            let backoff = policy.backoff(1);
+            assert!(backoff >= Duration::from_millis(5) && backoff <= Duration::from_millis(10));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let calendar = MockCalendar::start(Reply::FailFirst(2, StatusCode::BAD_GATEWAY)).await;
+
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig {
+            retry: retrying(3, Duration::from_secs(5)),
+            ..UpstreamsConfig::default()
+        }).unwrap();
+        upstreams.submit([0; 32]).await.unwrap();
+        assert_eq!(calendar.hits(), 3);
+
+        // Not retried
+        let calendar = MockCalendar::start(Reply::FailFirst(1, StatusCode::INTERNAL_SERVER_ERROR)).await;
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig {
+            retry: retrying(3, Duration::from_secs(5)),
+            ..UpstreamsConfig::default()
+        }).unwrap();
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::BadStatus(StatusCode::INTERNAL_SERVER_ERROR)));
+        assert_eq!(calendar.hits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_deadline() {
+        let calendar = MockCalendar::start(Reply::Delay(Duration::from_secs(10))).await;
+
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig {
+            retry: retrying(3, Duration::from_millis(200)),
+            ..UpstreamsConfig::default()
+        }).unwrap();
+        let start = Instant::now();
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::DeadlineExceeded));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_failover_deadline() {
+        let hung = MockCalendar::start(Reply::Delay(Duration::from_secs(60))).await;
+        let good = MockCalendar::start(Reply::Proof).await;
+
+        // A hung upstream times out and is retried, but leaves the next one time to succeed
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            ..RetryPolicy::default()
+        };
+        let upstreams = Upstreams::new([hung.url(), good.url()], UpstreamsConfig { retry, ..UpstreamsConfig::default() }).unwrap();
+        let proofs = upstreams.submit([0; 32]).await.unwrap();
+        assert_eq!(proofs, vec![good.proof()]);
+        assert_eq!((hung.hits(), good.hits()), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let calendar = MockCalendar::start(Reply::FailFirst(2, StatusCode::BAD_GATEWAY)).await;
+        let breaker = BreakerPolicy {
+            threshold: 2,
+            cooldown: Duration::from_millis(100),
+        };
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig { breaker, ..UpstreamsConfig::default() }).unwrap();
+
+        upstreams.submit([0; 32]).await.unwrap_err();
+        assert_eq!(upstreams.available(), Ok(()));
+        upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(upstreams.available().is_err());
+
+        // Open: fails immediately without contacting the upstream
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::CircuitOpen));
+        assert_eq!(calendar.hits(), 2);
+
+        // Half-open after the cooldown; the probe succeeds and closes the breaker
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(upstreams.available(), Ok(()));
+        upstreams.submit([0; 32]).await.unwrap();
+        assert_eq!(upstreams.upstreams[0].health.lock().unwrap().breaker, Breaker::Closed);
+        assert_eq!(calendar.hits(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let calendar = MockCalendar::start(Reply::FailFirst(1, StatusCode::BAD_GATEWAY)).await;
+        let upstreams = new_upstreams([calendar.url()], UpstreamMode::Failover(UpstreamOrder::InOrder));
+        assert!(upstreams.rounds().last_success.is_none());
+
+        upstreams.submit([0; 32]).await.unwrap_err();
+        let rounds = upstreams.rounds();
+        assert!(rounds.last_success.is_none());
+        assert_eq!(rounds.last_error.unwrap().error, "bad_status");
+
+        assert!(upstreams.failing_for().is_some());
+
+        upstreams.submit([0; 32]).await.unwrap();
+        assert!(upstreams.rounds().last_success.is_some());
+        assert_eq!(upstreams.failing_for(), None);
+
+        let status = upstreams.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].breaker, "closed");
+        assert_eq!((status[0].successes, status[0].failures, status[0].consecutive_failures), (1, 1, 0));
+        assert!(status[0].last_success_age.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_reopens() {
+        let calendar = MockCalendar::start(Reply::Status(StatusCode::BAD_GATEWAY)).await;
+        let breaker = BreakerPolicy {
+            threshold: 1,
+            cooldown: Duration::from_millis(100),
+        };
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig { breaker, ..UpstreamsConfig::default() }).unwrap();
+
+        upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(upstreams.available().is_err());
+
+        // A failed probe re-opens the breaker
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(upstreams.upstreams[0].try_begin_submit());
+        assert!(!upstreams.upstreams[0].try_begin_submit(), "only one probe at a time");
+        upstreams.record(&upstreams.upstreams[0], &Err(StampRequestError::DeadlineExceeded));
+        assert!(upstreams.available().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_fanout() {
+        let calendar1 = MockCalendar::start(Reply::Proof).await;
+        let calendar2 = MockCalendar::start(Reply::Proof).await;
+        let upstreams = new_upstreams([calendar1.url(), calendar2.url()], UpstreamMode::Fanout { quorum: 2 });
+        let breaker = |i: usize| upstreams.upstreams[i].health.lock().unwrap().breaker;
+
+        upstreams.upstreams[0].health.lock().unwrap().breaker = Breaker::HalfOpen { probing: false };
+        upstreams.upstreams[1].health.lock().unwrap().breaker = Breaker::Open { until: Instant::now() + Duration::from_secs(60) };
+
+        // Without a quorum nothing is submitted, and the half-open upstream's probe is released
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::CircuitOpen));
+        assert_eq!(breaker(0), Breaker::HalfOpen { probing: false });
+        assert_eq!((calendar1.hits(), calendar2.hits()), (0, 0));
+        assert!(upstreams.available().is_err());
+
+        // So it can be probed once the other upstream is back
+        upstreams.upstreams[1].health.lock().unwrap().breaker = Breaker::Closed;
+        assert_eq!(upstreams.available(), Ok(()));
+        upstreams.submit([0; 32]).await.unwrap();
+        assert_eq!(breaker(0), Breaker::Closed);
+        assert_eq!((calendar1.hits(), calendar2.hits()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_get_timestamp() {
+        let slow = MockCalendar::start(Reply::Delay(Duration::from_secs(2))).await;
+        let fast = MockCalendar::start(Reply::Proof).await;
+        let upstreams = new_upstreams([slow.url(), fast.url()], UpstreamMode::Failover(UpstreamOrder::InOrder));
+
+        // Upstreams are asked concurrently, so the slow one doesn't hold up the reply
+        let start = Instant::now();
+        let (proof, _) = upstreams.get_timestamp("00").await.unwrap().unwrap();
+        assert_eq!(proof, fast.proof());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Upstreams with open breakers aren't asked at all
+        for upstream in &upstreams.upstreams {
+            upstream.health.lock().unwrap().breaker = Breaker::Open { until: Instant::now() + Duration::from_secs(60) };
+        }
+        let err = upstreams.get_timestamp("00").await.unwrap_err();
+        assert!(matches!(err, StampRequestError::CircuitOpen));
+        assert_eq!((slow.hits(), fast.hits()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_client_config() {
+        let calendar = MockCalendar::start(Reply::Delay(Duration::from_millis(500))).await;
+
+        let mut client = ClientConfig {
+            timeout: Duration::from_millis(100),
+            ..ClientConfig::default()
+        };
+        client.headers.insert("authorization", "Bearer foo".parse().unwrap());
+        let upstreams = Upstreams::new([calendar.url()], UpstreamsConfig { client, ..UpstreamsConfig::default() }).unwrap();
+
+        let err = upstreams.submit([0; 32]).await.unwrap_err();
+        assert!(matches!(err, StampRequestError::Upstream(err) if err.is_timeout()));
+        assert_eq!(calendar.last_header("authorization").unwrap(), "Bearer foo");
+    }
+
+    #[test]
+    fn test_client_config_invalid() {
+        let config = ClientConfig {
+            ca_certs: vec!["/nonexistent/ca.pem".into()],
+            ..ClientConfig::default()
+        };
+        assert!(matches!(config.build(), Err(ClientConfigError::Read(_, _))));
+
+        let not_pem = std::env::temp_dir().join(format!("foxglove-test-{}.pem", std::process::id()));
+        std::fs::write(&not_pem, b"not a certificate").unwrap();
+        let config = ClientConfig {
+            ca_certs: vec![not_pem.clone()],
+            ..ClientConfig::default()
+        };
+        assert!(config.build().is_err());
+
+        let config = ClientConfig {
+            client_cert: Some((not_pem.clone(), not_pem.clone())),
+            ..ClientConfig::default()
+        };
+        assert!(matches!(config.build(), Err(ClientConfigError::ClientCert(_, _))));
+        std::fs::remove_file(not_pem).unwrap();
+    }
+}